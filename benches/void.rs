@@ -1,4 +1,5 @@
 use std::io::Cursor;
+use std::rc::Rc;
 
 use criterion::{criterion_group, criterion_main, Criterion};
 
@@ -8,14 +9,17 @@ use elb_logs_to_cloudwatch::{compile_pipelines, process_log, Pipeline, Pipelines
 const GOOD_LOGS: &str = include_str!("../tests/fixtures/logs.txt");
 
 fn criterion_benchmark(c: &mut Criterion) {
-    let raw_pipelines = Pipelines::new(vec![Pipeline {
+    let raw_pipelines = Rc::new(Pipelines::new(vec![Pipeline {
+        name: None,
         filter: "elb_status_code == 200 && user_agent matches \"(Android|axios)\"".to_string(),
-        output: OutputType::Void(VoidOutput),
-    }]);
-    let pipelines = compile_pipelines(&raw_pipelines);
+        output: Some(OutputType::Void(VoidOutput)),
+        outputs: vec![],
+        stop: false,
+    }]));
+    let pipelines = compile_pipelines(raw_pipelines.clone());
 
     c.bench_function("compile pipelines", |b| {
-        b.iter(|| compile_pipelines(&raw_pipelines))
+        b.iter(|| compile_pipelines(raw_pipelines.clone()))
     });
     c.bench_function("10", |b| {
         b.iter(|| process_log(Cursor::new(GOOD_LOGS), &pipelines).unwrap())
@@ -2,8 +2,20 @@ data "aws_iam_policy_document" "lambda" {
   statement {
     actions = [
       "cloudwatch:PutMetricData",
+      "dynamodb:GetItem",
+      "dynamodb:PutItem",
+      "dynamodb:UpdateItem",
+      "firehose:PutRecordBatch",
+      "kinesis:PutRecords",
+      "kms:Decrypt",
       "logs:PutLogEvents",
       "logs:CreateLogStream",
+      "s3:AbortMultipartUpload",
+      "s3:PutObject",
+      "secretsmanager:GetSecretValue",
+      "sns:Publish",
+      "sqs:SendMessage",
+      "ssm:GetParameter",
     ]
 
     resources = ["*"]
@@ -19,12 +19,13 @@ module "lambda" {
 
   // Add environment variables.
   environment = {
-    variables = {
-      // TODO: Remove dependency from this as the event has the bucket
-      BUCKET_NAME    = ""
-      RUST_BACKTRACE = 1
-      INSIDE_LAMBDA  = 1
-      PIPELINES      = var.pipelines
-    }
+    variables = merge(
+      {
+        RUST_BACKTRACE = 1
+        INSIDE_LAMBDA  = 1
+      },
+      var.pipelines_source == "" ? { PIPELINES = var.pipelines } : { PIPELINES_SOURCE = var.pipelines_source },
+      var.checkpoints == "" ? {} : { CHECKPOINTS = var.checkpoints },
+    )
   }
 }
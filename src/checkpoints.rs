@@ -0,0 +1,497 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use rusoto_core::{Region, RusotoError};
+use rusoto_dynamodb::{
+    AttributeValue, DynamoDb, DynamoDbClient, GetItemInput, PutItemError, PutItemInput,
+    UpdateItemError, UpdateItemInput,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::log_processing::Position;
+
+/// How long objects are remembered after they are leased or finished, through the `expires_at`
+/// TTL attribute, so abandoned leases are cleaned up too
+const RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A version of a log file, the same key can be written again with another content
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ObjectVersion {
+    pub bucket: String,
+    pub key: String,
+    pub etag: String,
+}
+
+impl ObjectVersion {
+    fn id(&self) -> String {
+        format!("s3://{}/{}#{}", self.bucket, self.key, self.etag)
+    }
+}
+
+/// Outcome of trying to start processing an object
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Claim {
+    /// The lease is ours until the object is completed or released, with the position a previous
+    /// attempt stopped at
+    Acquired(Option<Position>),
+    /// The object was already processed
+    Done,
+    /// Another invocation is processing the object and its lease did not expire yet
+    Leased,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Processing,
+    Done,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Processing => "processing",
+            Status::Done => "done",
+        }
+    }
+}
+
+/// Remembers which objects are processed so retried and duplicated events don't send the
+/// same lines twice
+pub(crate) trait CheckpointStore {
+    /// Leases the object, unless it is done or leased by someone else
+    fn acquire(&self, object: &ObjectVersion) -> Result<Claim>;
+    /// Marks the object as processed
+    fn complete(&self, object: &ObjectVersion) -> Result<()>;
+    /// Gives the lease up, so a retry doesn't wait for it to expire, saving the position to resume
+    /// from when it is known
+    fn release(&self, object: &ObjectVersion, position: Option<&Position>) -> Result<()>;
+}
+
+/// Where checkpoints are kept, `dynamodb:table` or `file:/path/checkpoints.json`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum CheckpointBackend {
+    DynamoDb { table: String },
+    File { path: PathBuf },
+}
+
+impl FromStr for CheckpointBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        if let Some(table) = backend.strip_prefix("dynamodb:") {
+            if table.is_empty() {
+                bail!("expected dynamodb:table, got {:?}", backend);
+            }
+            Ok(CheckpointBackend::DynamoDb {
+                table: table.to_string(),
+            })
+        } else if let Some(path) = backend.strip_prefix("file:") {
+            if path.is_empty() {
+                bail!("expected file:/path/checkpoints.json, got {:?}", backend);
+            }
+            Ok(CheckpointBackend::File { path: path.into() })
+        } else {
+            bail!(
+                "unsupported checkpoint backend {:?}, expected dynamodb:table or file:/path",
+                backend
+            )
+        }
+    }
+}
+
+impl CheckpointBackend {
+    pub fn store(&self, region: &Region, lease: Duration) -> Box<dyn CheckpointStore> {
+        match self {
+            CheckpointBackend::DynamoDb { table } => Box::new(DynamoDbStore {
+                client: DynamoDbClient::new(region.clone()),
+                table: table.clone(),
+                lease,
+                owner: uuid::Uuid::new_v4().to_string(),
+            }),
+            CheckpointBackend::File { path } => Box::new(FileStore {
+                path: path.clone(),
+                lease,
+            }),
+        }
+    }
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+/// Items keyed by the `id` string attribute, with `status`, `owner`, `lease_until`, `expires_at`
+/// and the `member_offset`, `member_line` and `file_line` of the position to resume from
+struct DynamoDbStore {
+    client: DynamoDbClient,
+    table: String,
+    lease: Duration,
+    /// Written on the items leased by this store, so it doesn't complete or release an object
+    /// whose lease expired and was taken by another invocation
+    owner: String,
+}
+
+fn string(value: &str) -> AttributeValue {
+    AttributeValue {
+        s: Some(value.to_string()),
+        ..Default::default()
+    }
+}
+
+fn number(value: i64) -> AttributeValue {
+    AttributeValue {
+        n: Some(value.to_string()),
+        ..Default::default()
+    }
+}
+
+/// The position saved in an item, if there is one
+fn position(item: &HashMap<String, AttributeValue>) -> Option<Position> {
+    let field = |name: &str| {
+        item.get(name)
+            .and_then(|value| value.n.as_ref())
+            .and_then(|value| value.parse().ok())
+    };
+    Some(Position {
+        member_offset: field("member_offset")?,
+        member_line: field("member_line")?,
+        line: field("file_line")?,
+    })
+}
+
+impl DynamoDbStore {
+    fn key(object: &ObjectVersion) -> HashMap<String, AttributeValue> {
+        let mut key = HashMap::new();
+        key.insert("id".to_string(), string(&object.id()));
+        key
+    }
+
+    /// `status` and `owner` are reserved words in expressions
+    fn attribute_names() -> Option<HashMap<String, String>> {
+        let mut names = HashMap::new();
+        names.insert("#status".to_string(), "status".to_string());
+        names.insert("#owner".to_string(), "owner".to_string());
+        Some(names)
+    }
+
+    /// Values of the condition that the object is still leased by this store
+    fn leased_values(&self) -> HashMap<String, AttributeValue> {
+        let mut values = HashMap::new();
+        values.insert(
+            ":processing".to_string(),
+            string(Status::Processing.as_str()),
+        );
+        values.insert(":owner".to_string(), string(&self.owner));
+        values
+    }
+
+    fn status(&self, object: &ObjectVersion) -> Result<Option<String>> {
+        let item = self
+            .client
+            .get_item(GetItemInput {
+                table_name: self.table.clone(),
+                key: Self::key(object),
+                consistent_read: Some(true),
+                ..Default::default()
+            })
+            .sync()
+            .with_context(|| format!("failed to get the checkpoint of {}", object.id()))?
+            .item;
+        Ok(item.and_then(|item| item.get("status").and_then(|status| status.s.clone())))
+    }
+}
+
+impl CheckpointStore for DynamoDbStore {
+    fn acquire(&self, object: &ObjectVersion) -> Result<Claim> {
+        let now = now();
+        let mut values = self.leased_values();
+        values.insert(":now".to_string(), number(now));
+        values.insert(
+            ":lease_until".to_string(),
+            number(now + self.lease.as_secs() as i64),
+        );
+        values.insert(
+            ":expires_at".to_string(),
+            number(now + RETENTION.as_secs() as i64),
+        );
+        let result = self
+            .client
+            .update_item(UpdateItemInput {
+                table_name: self.table.clone(),
+                key: Self::key(object),
+                update_expression: Some(
+                    "SET #status = :processing, #owner = :owner, lease_until = :lease_until, \
+                     expires_at = :expires_at"
+                        .to_string(),
+                ),
+                condition_expression: Some(
+                    "attribute_not_exists(id) OR (#status = :processing AND lease_until <= :now)"
+                        .to_string(),
+                ),
+                expression_attribute_names: Self::attribute_names(),
+                expression_attribute_values: Some(values),
+                return_values: Some("ALL_OLD".to_string()),
+                ..Default::default()
+            })
+            .sync();
+        match result {
+            Ok(output) => Ok(Claim::Acquired(
+                output.attributes.as_ref().and_then(position),
+            )),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {
+                match self.status(object)?.as_deref() {
+                    Some("done") => Ok(Claim::Done),
+                    _ => Ok(Claim::Leased),
+                }
+            }
+            Err(error) => Err(error)
+                .with_context(|| format!("failed to acquire the checkpoint of {}", object.id())),
+        }
+    }
+
+    fn complete(&self, object: &ObjectVersion) -> Result<()> {
+        let mut item = Self::key(object);
+        item.insert("status".to_string(), string(Status::Done.as_str()));
+        item.insert(
+            "expires_at".to_string(),
+            number(now() + RETENTION.as_secs() as i64),
+        );
+        let result = self
+            .client
+            .put_item(PutItemInput {
+                table_name: self.table.clone(),
+                item,
+                condition_expression: Some("#status = :processing AND #owner = :owner".to_string()),
+                expression_attribute_names: Self::attribute_names(),
+                expression_attribute_values: Some(self.leased_values()),
+                ..Default::default()
+            })
+            .sync();
+        match result {
+            Ok(_) => Ok(()),
+            Err(RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => bail!(
+                "the lease of {} expired and was taken by another invocation",
+                object.id()
+            ),
+            Err(error) => Err(error)
+                .with_context(|| format!("failed to complete the checkpoint of {}", object.id())),
+        }
+    }
+
+    fn release(&self, object: &ObjectVersion, position: Option<&Position>) -> Result<()> {
+        let mut values = self.leased_values();
+        values.insert(":expired".to_string(), number(0));
+        let mut update = "SET lease_until = :expired".to_string();
+        if let Some(position) = position {
+            update.push_str(
+                ", member_offset = :member_offset, member_line = :member_line, file_line = :line",
+            );
+            values.insert(
+                ":member_offset".to_string(),
+                number(position.member_offset as i64),
+            );
+            values.insert(
+                ":member_line".to_string(),
+                number(position.member_line as i64),
+            );
+            values.insert(":line".to_string(), number(position.line as i64));
+        }
+        let result = self
+            .client
+            .update_item(UpdateItemInput {
+                table_name: self.table.clone(),
+                key: Self::key(object),
+                update_expression: Some(update),
+                condition_expression: Some("#status = :processing AND #owner = :owner".to_string()),
+                expression_attribute_names: Self::attribute_names(),
+                expression_attribute_values: Some(values),
+                ..Default::default()
+            })
+            .sync();
+        match result {
+            Ok(_) | Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(()),
+            Err(error) => Err(error)
+                .with_context(|| format!("failed to release the checkpoint of {}", object.id())),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    status: Status,
+    #[serde(default)]
+    lease_until: i64,
+    #[serde(default)]
+    position: Option<Position>,
+}
+
+/// A JSON file of checkpoints by object, for the CLI and tests: it is not safe to share it
+/// between processes
+struct FileStore {
+    path: PathBuf,
+    lease: Duration,
+}
+
+impl FileStore {
+    fn read(&self) -> Result<HashMap<String, Checkpoint>> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read {}", self.path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("invalid checkpoints in {}", self.path.display()))
+    }
+
+    fn write(&self, checkpoints: &HashMap<String, Checkpoint>) -> Result<()> {
+        fs::write(&self.path, serde_json::to_string_pretty(checkpoints)?)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+}
+
+impl CheckpointStore for FileStore {
+    fn acquire(&self, object: &ObjectVersion) -> Result<Claim> {
+        let mut checkpoints = self.read()?;
+        let now = now();
+        let position = match checkpoints.get(&object.id()) {
+            Some(checkpoint) if checkpoint.status == Status::Done => return Ok(Claim::Done),
+            Some(checkpoint) if checkpoint.lease_until > now => return Ok(Claim::Leased),
+            Some(checkpoint) => checkpoint.position,
+            None => None,
+        };
+        checkpoints.insert(
+            object.id(),
+            Checkpoint {
+                status: Status::Processing,
+                lease_until: now + self.lease.as_secs() as i64,
+                position,
+            },
+        );
+        self.write(&checkpoints)?;
+        Ok(Claim::Acquired(position))
+    }
+
+    fn complete(&self, object: &ObjectVersion) -> Result<()> {
+        let mut checkpoints = self.read()?;
+        checkpoints.insert(
+            object.id(),
+            Checkpoint {
+                status: Status::Done,
+                lease_until: 0,
+                position: None,
+            },
+        );
+        self.write(&checkpoints)
+    }
+
+    fn release(&self, object: &ObjectVersion, position: Option<&Position>) -> Result<()> {
+        let mut checkpoints = self.read()?;
+        if let Some(checkpoint) = checkpoints.get_mut(&object.id()) {
+            if checkpoint.status == Status::Processing {
+                checkpoint.lease_until = 0;
+                if let Some(position) = position {
+                    checkpoint.position = Some(*position);
+                }
+                self.write(&checkpoints)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    use crate::checkpoints::{CheckpointBackend, CheckpointStore, Claim, FileStore, ObjectVersion};
+    use crate::log_processing::Position;
+
+    fn object(etag: &str) -> ObjectVersion {
+        ObjectVersion {
+            bucket: "elb-logs".to_string(),
+            key: "AWSLogs/file.log.gz".to_string(),
+            etag: etag.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(
+            CheckpointBackend::DynamoDb {
+                table: "checkpoints".to_string()
+            },
+            "dynamodb:checkpoints".parse().unwrap()
+        );
+        assert_eq!(
+            CheckpointBackend::File {
+                path: "/tmp/checkpoints.json".into()
+            },
+            "file:/tmp/checkpoints.json".parse().unwrap()
+        );
+        assert!("dynamodb:".parse::<CheckpointBackend>().is_err());
+        assert!("redis://localhost".parse::<CheckpointBackend>().is_err());
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = env::temp_dir().join(format!("checkpoints-{}.json", uuid::Uuid::new_v4()));
+        let store = FileStore {
+            path: path.clone(),
+            lease: Duration::from_secs(900),
+        };
+
+        assert_eq!(Claim::Acquired(None), store.acquire(&object("1")).unwrap());
+        assert_eq!(Claim::Leased, store.acquire(&object("1")).unwrap());
+        // The key was overwritten, it is a new object
+        assert_eq!(Claim::Acquired(None), store.acquire(&object("2")).unwrap());
+
+        store.release(&object("1"), None).unwrap();
+        assert_eq!(Claim::Acquired(None), store.acquire(&object("1")).unwrap());
+        store.complete(&object("1")).unwrap();
+        assert_eq!(Claim::Done, store.acquire(&object("1")).unwrap());
+        // Done objects are not released
+        store.release(&object("1"), None).unwrap();
+        assert_eq!(Claim::Done, store.acquire(&object("1")).unwrap());
+
+        let position = Position {
+            member_offset: 1024,
+            member_line: 10,
+            line: 250,
+        };
+        assert_eq!(Claim::Acquired(None), store.acquire(&object("4")).unwrap());
+        store.release(&object("4"), Some(&position)).unwrap();
+        assert_eq!(
+            Claim::Acquired(Some(position)),
+            store.acquire(&object("4")).unwrap()
+        );
+        // A failure without position keeps the previous one
+        store.release(&object("4"), None).unwrap();
+        assert_eq!(
+            Claim::Acquired(Some(position)),
+            store.acquire(&object("4")).unwrap()
+        );
+
+        let expired = FileStore {
+            path: path.clone(),
+            lease: Duration::from_secs(0),
+        };
+        assert_eq!(
+            Claim::Acquired(None),
+            expired.acquire(&object("3")).unwrap()
+        );
+        assert_eq!(
+            Claim::Acquired(None),
+            expired.acquire(&object("3")).unwrap()
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+}
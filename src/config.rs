@@ -1,19 +1,110 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
 use rusoto_core::Region;
+use structopt::clap::{AppSettings, Error, ErrorKind};
 use structopt::StructOpt;
 
+use crate::checkpoints::{CheckpointBackend, CheckpointStore};
 use crate::pipelines::Pipelines;
+use crate::pipelines_file;
+use crate::pipelines_source::{PipelinesCache, PipelinesSource};
 
 #[derive(Debug, StructOpt)]
+#[structopt(setting = AppSettings::SubcommandsNegateReqs)]
 pub(crate) struct Config {
+    /// Defaults to the region of the environment or profile
     #[structopt(short, long, env)]
-    pub aws_region: Region,
-    #[structopt(short, long, env)]
-    pub pipelines: Pipelines,
+    aws_region: Option<Region>,
+    /// Pipelines as a JSON string
+    #[structopt(
+        short,
+        long,
+        env,
+        required_unless_one = &["pipelines-file", "pipelines-source"],
+        conflicts_with_all = &["pipelines-file", "pipelines-source"]
+    )]
+    pipelines: Option<String>,
+    /// JSON, YAML or TOML file with the pipelines, or a directory whose `*.yaml` files are merged
+    #[structopt(long, env, parse(from_os_str), conflicts_with = "pipelines-source")]
+    pipelines_file: Option<PathBuf>,
+    /// `s3://bucket/key` or `ssm:/path/param` to fetch the pipelines from, once per container
+    #[structopt(long, env)]
+    pipelines_source: Option<PipelinesSource>,
+    /// Seconds before checking if the pipelines source changed
+    #[structopt(long, env, default_value = "300")]
+    pipelines_cache_ttl: u64,
+    /// `dynamodb:table` or `file:/path/checkpoints.json` to skip the objects already processed
+    #[structopt(long, env)]
+    checkpoints: Option<CheckpointBackend>,
+    /// Seconds an invocation has to process an object before another one can take it over
+    #[structopt(long, env, default_value = "900")]
+    checkpoint_lease: u64,
+    /// Seconds before the lambda timeout to stop processing a file and save where it stopped
+    #[structopt(long, env, default_value = "30")]
+    checkpoint_margin: u64,
+    /// Bucket of `bucket_keys`, the lambda reads the bucket of every event record instead
     #[structopt(short, long, env)]
-    pub bucket_name: String,
+    pub bucket_name: Option<String>,
     pub bucket_keys: Vec<String>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+    /// Pipelines loaded from `pipelines` or `pipelines_file`
+    #[structopt(skip)]
+    loaded_pipelines: Option<Pipelines>,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) enum Command {
+    /// Prints the JSON Schema of the pipelines configuration
+    Schema,
+}
+
+impl Config {
+    pub fn aws_region(&self) -> Region {
+        self.aws_region.clone().unwrap_or_default()
+    }
+
+    /// Pipelines given directly or through a file, `None` when they come from a remote source
+    pub fn take_pipelines(&mut self) -> Option<Pipelines> {
+        self.loaded_pipelines.take()
+    }
+
+    pub fn pipelines_cache(&self) -> Option<PipelinesCache> {
+        self.pipelines_source.clone().map(|source| {
+            PipelinesCache::new(source, Duration::from_secs(self.pipelines_cache_ttl))
+        })
+    }
+
+    pub fn checkpoint_lease(&self) -> Duration {
+        Duration::from_secs(self.checkpoint_lease)
+    }
+
+    pub fn checkpoint_margin(&self) -> Duration {
+        Duration::from_secs(self.checkpoint_margin)
+    }
+
+    pub fn checkpoint_store(&self) -> Option<Box<dyn CheckpointStore>> {
+        self.checkpoints
+            .as_ref()
+            .map(|backend| backend.store(&self.aws_region(), self.checkpoint_lease()))
+    }
 }
 
 pub(crate) fn from_args() -> Config {
-    Config::from_args()
+    let mut config = Config::from_args();
+    // Parsed here rather than by clap, as their references need the region
+    let region = config.aws_region();
+    let loaded = match (&config.pipelines, &config.pipelines_file) {
+        (Some(json), _) => Pipelines::interpolated(json, &region)
+            .map_err(|error| format!("Invalid pipelines: {:#}", error)),
+        (None, Some(path)) => pipelines_file::load(path, &region)
+            .map_err(|error| format!("Invalid pipelines file: {:#}", error)),
+        (None, None) => return config,
+    };
+    match loaded {
+        Ok(pipelines) => config.loaded_pipelines = Some(pipelines),
+        Err(message) => Error::with_description(&message, ErrorKind::ValueValidation).exit(),
+    }
+    config
 }
@@ -7,6 +7,18 @@ pub enum HandlerError {
     Unknown(anyhow::Error),
     #[error("error downloading log file {0:?}")]
     S3Error(anyhow::Error),
+    #[error(
+        "stopped processing {object} after line {line} before the timeout, a retry resumes there"
+    )]
+    Interrupted { object: String, line: u64 },
+    #[error("invalid event record: {0}")]
+    InvalidRecord(String),
+    #[error("{failed} of {total} records failed, the first one with: {first}")]
+    RecordsFailed {
+        failed: usize,
+        total: usize,
+        first: Box<HandlerError>,
+    },
 }
 
 impl LambdaErrorExt for HandlerError {
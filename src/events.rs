@@ -0,0 +1,321 @@
+use aws_lambda_events::event::s3::{S3Event, S3EventRecord};
+use percent_encoding::percent_decode_str;
+use serde_json::Value;
+
+use crate::error::HandlerError;
+
+/// A log file to process
+#[derive(Debug, PartialEq)]
+pub(crate) struct S3Object {
+    pub bucket: String,
+    pub key: String,
+}
+
+#[derive(Debug)]
+pub(crate) enum Event {
+    /// Objects notified straight to the lambda, by S3 or SNS
+    Direct(Vec<Result<S3Object, HandlerError>>),
+    /// SQS messages, which are retried one by one
+    Sqs(Vec<SqsMessage>),
+}
+
+#[derive(Debug)]
+pub(crate) struct SqsMessage {
+    pub message_id: String,
+    pub objects: Result<Vec<S3Object>, HandlerError>,
+}
+
+/// Detects the kind of event from its payload
+pub(crate) fn parse_event(event: Value) -> Result<Event, HandlerError> {
+    let first = event
+        .get("Records")
+        .and_then(Value::as_array)
+        .and_then(|records| records.first());
+    let source = first.and_then(|record| {
+        record
+            .get("eventSource")
+            .or_else(|| record.get("EventSource"))
+            .and_then(Value::as_str)
+    });
+
+    match source {
+        Some("aws:sqs") => Ok(Event::Sqs(
+            records(&event).iter().map(parse_sqs_message).collect(),
+        )),
+        Some("aws:sns") => Ok(Event::Direct(
+            records(&event)
+                .iter()
+                .flat_map(
+                    |record| match record.pointer("/Sns/Message").and_then(Value::as_str) {
+                        Some(message) => parse_notification_str(message),
+                        None => vec![Err(invalid("missing SNS message"))],
+                    },
+                )
+                .collect(),
+        )),
+        _ => Ok(Event::Direct(parse_notification(event)?)),
+    }
+}
+
+fn records(event: &Value) -> &[Value] {
+    event["Records"].as_array().map_or(&[], Vec::as_slice)
+}
+
+fn parse_sqs_message(record: &Value) -> SqsMessage {
+    let message_id = record["messageId"].as_str().unwrap_or_default().to_string();
+    let objects = match record["body"].as_str() {
+        Some(body) => parse_notification_str(body).into_iter().collect(),
+        None => Err(invalid("missing SQS message body")),
+    };
+    SqsMessage {
+        message_id,
+        objects,
+    }
+}
+
+fn parse_notification_str(notification: &str) -> Vec<Result<S3Object, HandlerError>> {
+    match serde_json::from_str(notification) {
+        Ok(notification) => {
+            parse_notification(notification).unwrap_or_else(|error| vec![Err(error)])
+        }
+        Err(error) => vec![Err(invalid(&format!("message is not JSON: {}", error)))],
+    }
+}
+
+/// Parses an S3 event or an EventBridge `Object Created` event, possibly inside an SNS envelope
+fn parse_notification(
+    notification: Value,
+) -> Result<Vec<Result<S3Object, HandlerError>>, HandlerError> {
+    if notification["Type"] == "Notification" {
+        return match notification["Message"].as_str() {
+            Some(message) => Ok(parse_notification_str(message)),
+            None => Err(invalid("missing SNS message")),
+        };
+    }
+    if notification["source"] == "aws.s3" && notification["detail-type"] == "Object Created" {
+        return Ok(vec![eventbridge_location(&notification["detail"])]);
+    }
+    // Sent by S3 when notifications are configured, there is nothing to process
+    if notification["Event"] == "s3:TestEvent" {
+        return Ok(vec![]);
+    }
+    let event: S3Event = serde_json::from_value(notification)
+        .map_err(|error| invalid(&format!("unsupported event: {}", error)))?;
+    Ok(event.records.iter().map(record_location).collect())
+}
+
+/// Bucket and decoded key of the log file a record points to
+fn record_location(record: &S3EventRecord) -> Result<S3Object, HandlerError> {
+    let bucket = record
+        .s3
+        .bucket
+        .name
+        .as_deref()
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| invalid("missing bucket name"))?;
+    let key = record
+        .s3
+        .object
+        .key
+        .as_deref()
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| invalid(&format!("missing key in bucket {}", bucket)))?;
+    Ok(S3Object {
+        bucket: bucket.to_string(),
+        key: decode_key(key)?,
+    })
+}
+
+fn eventbridge_location(detail: &Value) -> Result<S3Object, HandlerError> {
+    let bucket = detail
+        .pointer("/bucket/name")
+        .and_then(Value::as_str)
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| invalid("missing bucket name"))?;
+    let key = detail
+        .pointer("/object/key")
+        .and_then(Value::as_str)
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| invalid(&format!("missing key in bucket {}", bucket)))?;
+    Ok(S3Object {
+        bucket: bucket.to_string(),
+        key: decode_key(key)?,
+    })
+}
+
+/// Event notifications URL-encode keys, with spaces as `+`
+fn decode_key(key: &str) -> Result<String, HandlerError> {
+    percent_decode_str(&key.replace('+', " "))
+        .decode_utf8()
+        .map(|key| key.into_owned())
+        .map_err(|_| invalid(&format!("key {} is not valid UTF-8", key)))
+}
+
+fn invalid(reason: &str) -> HandlerError {
+    HandlerError::InvalidRecord(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::error::HandlerError;
+    use crate::events::{decode_key, parse_event, Event, S3Object};
+
+    fn s3_event(key: &str) -> serde_json::Value {
+        json!({
+            "Records": [{
+                "eventVersion": "2.1",
+                "eventSource": "aws:s3",
+                "awsRegion": "eu-central-1",
+                "eventTime": "2020-02-20T10:00:00.000Z",
+                "eventName": "ObjectCreated:Put",
+                "userIdentity": {"principalId": "AWS:EXAMPLE"},
+                "requestParameters": {"sourceIPAddress": "127.0.0.1"},
+                "responseElements": {},
+                "s3": {
+                    "s3SchemaVersion": "1.0",
+                    "configurationId": "logs",
+                    "bucket": {"name": "elb-logs", "ownerIdentity": {"principalId": "EXAMPLE"}, "arn": "arn:aws:s3:::elb-logs"},
+                    "object": {"key": key, "size": 1024, "eTag": "0123456789abcdef", "sequencer": "0A1B2C3D4E5F678901"}
+                }
+            }]
+        })
+    }
+
+    fn object(key: &str) -> S3Object {
+        S3Object {
+            bucket: "elb-logs".to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_decode_key() {
+        assert_eq!(
+            "AWSLogs/123/elasticloadbalancing/eu-central-1/2020/02/20/my lb=1+2.log.gz",
+            decode_key(
+                "AWSLogs/123/elasticloadbalancing/eu-central-1/2020/02/20/my+lb%3D1%2B2.log.gz"
+            )
+            .unwrap()
+        );
+        assert_eq!("plain/key.log.gz", decode_key("plain/key.log.gz").unwrap());
+        assert_eq!("caf\u{e9}", decode_key("caf%C3%A9").unwrap());
+        assert!(decode_key("broken%FF").is_err());
+    }
+
+    #[test]
+    fn test_parse_s3_event() {
+        match parse_event(s3_event("my+file.log.gz")).unwrap() {
+            Event::Direct(objects) => {
+                assert_eq!(object("my file.log.gz"), *objects[0].as_ref().unwrap())
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_missing_location() {
+        let mut event = s3_event("fine.log.gz");
+        let mut record = event["Records"][0].clone();
+        record["s3"]["bucket"]["name"] = "".into();
+        event["Records"].as_array_mut().unwrap().push(record);
+        for key in &[json!(""), json!(null)] {
+            let mut record = event["Records"][0].clone();
+            record["s3"]["object"]["key"] = key.clone();
+            event["Records"].as_array_mut().unwrap().push(record);
+        }
+
+        // Only the broken records fail, the others are still processed
+        let objects = match parse_event(event).unwrap() {
+            Event::Direct(objects) => objects,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(4, objects.len());
+        assert_eq!(object("fine.log.gz"), *objects[0].as_ref().unwrap());
+        let reasons: Vec<String> = objects[1..]
+            .iter()
+            .map(|object| match object {
+                Err(HandlerError::InvalidRecord(reason)) => reason.clone(),
+                object => panic!("unexpected object {:?}", object),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "missing bucket name",
+                "missing key in bucket elb-logs",
+                "missing key in bucket elb-logs"
+            ],
+            reasons
+        );
+    }
+
+    #[test]
+    fn test_parse_eventbridge_event() {
+        let event = json!({
+            "version": "0",
+            "id": "17793124-05d4-b198-2fde-7ededc63b103",
+            "detail-type": "Object Created",
+            "source": "aws.s3",
+            "account": "123456789012",
+            "time": "2020-02-20T10:00:00Z",
+            "region": "eu-central-1",
+            "resources": ["arn:aws:s3:::elb-logs"],
+            "detail": {
+                "version": "0",
+                "bucket": {"name": "elb-logs"},
+                "object": {"key": "AWSLogs/my+file.log.gz", "size": 1024, "etag": "0123456789abcdef"},
+                "request-id": "N4N7GDK58NMKJ12R",
+                "reason": "PutObject"
+            }
+        });
+        match parse_event(event.clone()).unwrap() {
+            Event::Direct(objects) => {
+                assert_eq!(
+                    object("AWSLogs/my file.log.gz"),
+                    *objects[0].as_ref().unwrap()
+                )
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let sqs = json!({"Records": [{"messageId": "1", "eventSource": "aws:sqs", "body": event.to_string()}]});
+        match parse_event(sqs).unwrap() {
+            Event::Sqs(messages) => assert_eq!(
+                vec![object("AWSLogs/my file.log.gz")],
+                *messages[0].objects.as_ref().unwrap()
+            ),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_parse_sqs_event() {
+        let sns_envelope = json!({
+            "Type": "Notification",
+            "MessageId": "sns-1",
+            "Message": s3_event("from-sns.log.gz").to_string(),
+        });
+        let event = json!({
+            "Records": [
+                {"messageId": "1", "eventSource": "aws:sqs", "body": s3_event("direct.log.gz").to_string()},
+                {"messageId": "2", "eventSource": "aws:sqs", "body": sns_envelope.to_string()},
+                {"messageId": "3", "eventSource": "aws:sqs", "body": "not json"},
+            ]
+        });
+        let messages = match parse_event(event).unwrap() {
+            Event::Sqs(messages) => messages,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(
+            vec![object("direct.log.gz")],
+            *messages[0].objects.as_ref().unwrap()
+        );
+        assert_eq!(
+            vec![object("from-sns.log.gz")],
+            *messages[1].objects.as_ref().unwrap()
+        );
+        assert_eq!("3", messages[2].message_id);
+        assert!(messages[2].objects.is_err());
+    }
+}
@@ -1,46 +1,272 @@
 use std::result::Result;
 
-use aws_lambda_events::event::s3::S3Event;
+use anyhow::anyhow;
 use lambda_runtime::Context;
-use log::{error, info, trace};
-
-use crate::config;
+use log::{error, info, trace, warn};
+use serde::Serialize;
+use serde_json::Value;
 
+use crate::checkpoints::{Claim, ObjectVersion};
 use crate::error::HandlerError;
-use crate::log_processing::process_log;
-use crate::pipelines::compile_pipelines;
-use crate::s3::open_s3_file;
-use std::time::Instant;
-
-pub fn handler(event: S3Event, _context: Context) -> Result<(), HandlerError> {
-    trace!("Got an S3 event {:#?}", event);
-    let config = config::from_args();
-    let pipelines = compile_pipelines(&config.pipelines);
+use crate::events::{parse_event, Event, S3Object, SqsMessage};
+use crate::log_processing::{process_gzip_log, Position, ProcessLogOutput};
+use crate::s3::{open_s3_file, open_s3_file_at, s3_file_etag};
+use crate::state::State;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Serialize)]
+pub(crate) struct HandlerOutput {
+    #[serde(flatten)]
+    pub stats: ProcessLogOutput,
+    /// Lets SQS retry only the failed messages, the event source mapping needs
+    /// `ReportBatchItemFailures` enabled
+    #[serde(rename = "batchItemFailures", skip_serializing_if = "Option::is_none")]
+    pub batch_item_failures: Option<Vec<BatchItemFailure>>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BatchItemFailure {
+    #[serde(rename = "itemIdentifier")]
+    pub item_identifier: String,
+}
+
+pub(crate) fn handler(
+    event: Value,
+    context: Context,
+    state: &mut State,
+) -> Result<HandlerOutput, HandlerError> {
+    trace!("Got an event {:#?}", event);
+    state.refresh_pipelines();
     let start_time = Instant::now();
-    let mut total_lines = 0;
-    let mut matched_lines = 0;
-
-    for record in event.records {
-        let buffer = open_s3_file(
-            &record.s3.bucket.name.unwrap(),
-            &record.s3.object.key.unwrap(),
-            &config,
-        )
-        .map_err(|error| {
-            error!("Failed to read S3 file {:?}", error);
-            HandlerError::S3Error(error)
-        })?;
-        let output = process_log(buffer, &pipelines).map_err(|error| {
-            error!("Failed to process log file {:?}", error);
-            HandlerError::Unknown(error)
-        })?;
-        total_lines += output.total_lines;
-        matched_lines += output.matched_lines;
-    }
+    // Leaves time to flush the outputs and save where the file stopped
+    let remaining = Duration::from_millis(context.get_time_remaining_millis().max(0) as u64);
+    let deadline = state.checkpoints().map(|_| {
+        start_time
+            + remaining
+                .checked_sub(state.config.checkpoint_margin())
+                .unwrap_or_default()
+    });
+    let result = parse_event(event).and_then(|event| match event {
+        Event::Direct(objects) => process_objects(objects, state, deadline),
+        Event::Sqs(messages) => Ok(process_messages(messages, state, deadline)),
+    });
+
+    // Outputs live as long as the container, nothing can wait in their buffers for the next event
+    let flushed = state.pipelines().finish();
+    let output = result?;
+    flushed.map_err(HandlerError::Unknown)?;
+
     let end_time = start_time.elapsed();
+    let total = &output.stats;
+    let pipelines: Vec<String> = total.pipelines.iter().map(ToString::to_string).collect();
     info!(
-        "Finished processing {} lines with {} matches in {:?}",
-        total_lines, matched_lines, end_time
+        "Finished processing {} lines with {} matches in {:?} [{}]",
+        total.total_lines,
+        total.matched_lines,
+        end_time,
+        pipelines.join(", ")
     );
-    Ok(())
+    Ok(output)
+}
+
+fn process_objects(
+    objects: Vec<Result<S3Object, HandlerError>>,
+    state: &State,
+    deadline: Option<Instant>,
+) -> Result<HandlerOutput, HandlerError> {
+    let mut total = ProcessLogOutput::default();
+    let records = objects.len();
+    let mut errors = vec![];
+
+    // A broken record doesn't keep the others from being processed
+    for object in objects {
+        match object.and_then(|object| process_object(&object, state, deadline)) {
+            Ok(output) => total.merge(output),
+            Err(error) => {
+                error!("Failed to process record {:?}", error);
+                errors.push(error);
+            }
+        }
+    }
+    if errors.is_empty() {
+        return Ok(HandlerOutput {
+            stats: total,
+            batch_item_failures: None,
+        });
+    }
+    Err(HandlerError::RecordsFailed {
+        failed: errors.len(),
+        total: records,
+        first: Box::new(errors.remove(0)),
+    })
+}
+
+fn process_messages(
+    messages: Vec<SqsMessage>,
+    state: &State,
+    deadline: Option<Instant>,
+) -> HandlerOutput {
+    let mut total = ProcessLogOutput::default();
+    let mut failures = vec![];
+
+    for message in messages {
+        let result = message.objects.and_then(|objects| {
+            objects
+                .iter()
+                .map(|object| process_object(object, state, deadline))
+                .collect::<Result<Vec<_>, _>>()
+        });
+        match result {
+            Ok(outputs) => outputs.into_iter().for_each(|output| total.merge(output)),
+            Err(error) => {
+                error!(
+                    "Failed to process message {}: {:?}",
+                    message.message_id, error
+                );
+                failures.push(BatchItemFailure {
+                    item_identifier: message.message_id,
+                });
+            }
+        }
+    }
+    HandlerOutput {
+        stats: total,
+        batch_item_failures: Some(failures),
+    }
+}
+
+/// Processes a log file until its end or the deadline, which only applies with checkpoints, as
+/// they let the retry resume where the file stopped
+pub(crate) fn process_object(
+    object: &S3Object,
+    state: &State,
+    deadline: Option<Instant>,
+) -> Result<ProcessLogOutput, HandlerError> {
+    if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+        return Err(HandlerError::Interrupted {
+            object: format!("s3://{}/{}", object.bucket, object.key),
+            line: 0,
+        });
+    }
+    let read_error = |error: anyhow::Error| {
+        error!("Failed to read S3 file {:?}", error);
+        HandlerError::S3Error(error)
+    };
+    let etag = match state.checkpoints() {
+        Some(_) => {
+            s3_file_etag(&state.s3_client, &object.bucket, &object.key).map_err(read_error)?
+        }
+        None => None,
+    };
+    let (checkpoint, position) = match (state.checkpoints(), etag) {
+        (Some(checkpoints), Some(etag)) => {
+            let version = ObjectVersion {
+                bucket: object.bucket.clone(),
+                key: object.key.clone(),
+                etag,
+            };
+            match checkpoints
+                .acquire(&version)
+                .map_err(HandlerError::Unknown)?
+            {
+                Claim::Acquired(position) => {
+                    (Some((checkpoints, version)), position.unwrap_or_default())
+                }
+                Claim::Done => {
+                    info!(
+                        "Skipping s3://{}/{}, it was already processed",
+                        object.bucket, object.key
+                    );
+                    return Ok(ProcessLogOutput::default());
+                }
+                Claim::Leased => {
+                    return Err(HandlerError::Unknown(anyhow!(
+                        "s3://{}/{} is being processed by another invocation",
+                        object.bucket,
+                        object.key
+                    )))
+                }
+            }
+        }
+        (Some(_), None) => {
+            warn!(
+                "s3://{}/{} has no ETag, it is processed without checkpoint",
+                object.bucket, object.key
+            );
+            (None, Position::default())
+        }
+        (None, _) => (None, Position::default()),
+    };
+
+    let result = match &checkpoint {
+        // The version leased is the one downloaded, a newer one fails the record
+        Some((_, version)) => {
+            if position.member_offset > 0 {
+                info!(
+                    "Resuming s3://{}/{} after line {}",
+                    object.bucket, object.key, position.line
+                );
+            }
+            open_s3_file_at(
+                &state.s3_client,
+                &object.bucket,
+                &object.key,
+                &version.etag,
+                position.member_offset,
+            )
+            .map_err(read_error)
+            .and_then(|file| process_file(file, position, state, deadline))
+        }
+        None => open_s3_file(&state.s3_client, &object.bucket, &object.key)
+            .map_err(read_error)
+            .and_then(|file| process_file(file, position, state, None)),
+    };
+    let (checkpoints, version) = match checkpoint {
+        Some(checkpoint) => checkpoint,
+        None => return result.map(|(output, _)| output),
+    };
+
+    // Processing fails when the outputs can't flush, so a file is only done, and a position only
+    // saved, once the lines before it were sent. Otherwise the previous position is kept
+    let saved = match &result {
+        Ok((_, None)) => checkpoints.complete(&version),
+        Ok((_, Some(stopped))) => checkpoints.release(&version, Some(stopped)),
+        Err(_) => checkpoints.release(&version, None),
+    };
+    if let Err(error) = saved {
+        error!(
+            "Failed to save the checkpoint of s3://{}/{}, it stays leased and is only processed \
+             again once the lease expires in {}s: {:?}",
+            object.bucket,
+            object.key,
+            state.config.checkpoint_lease().as_secs(),
+            error
+        );
+    }
+    match result? {
+        (output, None) => Ok(output),
+        (output, Some(stopped)) => {
+            info!(
+                "Stopped s3://{}/{} before the deadline after {} lines with {} matches",
+                object.bucket, object.key, output.total_lines, output.matched_lines
+            );
+            Err(HandlerError::Interrupted {
+                object: format!("s3://{}/{}", object.bucket, object.key),
+                line: stopped.line,
+            })
+        }
+    }
+}
+
+fn process_file<R: Read>(
+    compressed: R,
+    position: Position,
+    state: &State,
+    deadline: Option<Instant>,
+) -> Result<(ProcessLogOutput, Option<Position>), HandlerError> {
+    process_gzip_log(compressed, position, state.pipelines(), deadline).map_err(|error| {
+        error!("Failed to process log file {:?}", error);
+        HandlerError::Unknown(error)
+    })
 }
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::env;
+
+use anyhow::{bail, Context, Result};
+use rusoto_core::Region;
+use rusoto_secretsmanager::{GetSecretValueRequest, SecretsManager, SecretsManagerClient};
+use rusoto_ssm::{GetParameterRequest, Ssm, SsmClient};
+
+const SSM_PREFIX: &str = "ssm:";
+const SECRETS_MANAGER_PREFIX: &str = "secretsmanager:";
+
+/// Replaces `${ENV_VAR}`, `${ENV_VAR:-default}`, `${ssm:/path/param}` and
+/// `${secretsmanager:secret-id}` references in a pipelines configuration before it is
+/// deserialized. Values inside double quoted strings are escaped like JSON strings, which YAML
+/// and TOML double quoted strings accept too, others are inserted as they are. `$${` is kept as a
+/// literal `${`.
+pub(crate) fn interpolate(content: &str, region: &Region) -> Result<String> {
+    let mut cache = HashMap::new();
+    interpolate_with(content, |name| {
+        if let Some(value) = cache.get(name) {
+            return Ok(Some(value.clone()));
+        }
+        let value = lookup(name, region)?;
+        if let Some(value) = &value {
+            cache.insert(name.to_string(), value.clone());
+        }
+        Ok(value)
+    })
+}
+
+fn lookup(name: &str, region: &Region) -> Result<Option<String>> {
+    if let Some(name) = name.strip_prefix(SSM_PREFIX) {
+        let parameter = SsmClient::new(region.clone())
+            .get_parameter(GetParameterRequest {
+                name: name.to_string(),
+                with_decryption: Some(true),
+            })
+            .sync()
+            .with_context(|| format!("failed to get parameter {}", name))?
+            .parameter
+            .and_then(|parameter| parameter.value);
+        Ok(parameter)
+    } else if let Some(secret_id) = name.strip_prefix(SECRETS_MANAGER_PREFIX) {
+        let secret = SecretsManagerClient::new(region.clone())
+            .get_secret_value(GetSecretValueRequest {
+                secret_id: secret_id.to_string(),
+                ..Default::default()
+            })
+            .sync()
+            .with_context(|| format!("failed to get secret {}", secret_id))?
+            .secret_string;
+        Ok(secret)
+    } else {
+        Ok(env::var(name).ok())
+    }
+}
+
+/// Follows the strings and comments of the content written so far. Only double quoted strings
+/// get escaped values, YAML single quoted and TOML literal strings have no escape sequences.
+#[derive(Default)]
+struct Quotes {
+    state: Quoted,
+    escaped: bool,
+    previous: Option<char>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Quoted {
+    Nothing,
+    DoubleQuotes,
+    SingleQuotes,
+    Comment,
+}
+
+impl Default for Quoted {
+    fn default() -> Self {
+        Quoted::Nothing
+    }
+}
+
+impl Quotes {
+    fn in_string(&self) -> bool {
+        self.state == Quoted::DoubleQuotes
+    }
+
+    fn scan(&mut self, text: &str) {
+        for character in text.chars() {
+            // A `'` or `#` within a word, like in `don't`, starts neither a string nor a comment
+            let starts_token = self.previous.map_or(true, |previous| {
+                previous.is_whitespace() || "[{,:=".contains(previous)
+            });
+            self.state = match (self.state, character) {
+                (Quoted::DoubleQuotes, _) if self.escaped => {
+                    self.escaped = false;
+                    Quoted::DoubleQuotes
+                }
+                (Quoted::DoubleQuotes, '\\') => {
+                    self.escaped = true;
+                    Quoted::DoubleQuotes
+                }
+                (Quoted::DoubleQuotes, '"')
+                | (Quoted::SingleQuotes, '\'')
+                | (Quoted::Comment, '\n') => Quoted::Nothing,
+                (Quoted::Nothing, '"') => Quoted::DoubleQuotes,
+                (Quoted::Nothing, '\'') if starts_token => Quoted::SingleQuotes,
+                (Quoted::Nothing, '#') if starts_token => Quoted::Comment,
+                (state, _) => state,
+            };
+            self.previous = Some(character);
+        }
+    }
+}
+
+/// Escapes a value like the content of a JSON string
+fn escape(value: &str) -> Result<String> {
+    let quoted = serde_json::to_string(value)?;
+    Ok(quoted[1..quoted.len() - 1].to_string())
+}
+
+fn interpolate_with<F>(content: &str, mut lookup: F) -> Result<String>
+where
+    F: FnMut(&str) -> Result<Option<String>>,
+{
+    let mut result = String::with_capacity(content.len());
+    let mut rest = content;
+    let mut quotes = Quotes::default();
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            quotes.scan(&rest[..start - 1]);
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        quotes.scan(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => bail!("unterminated reference {:?}", &rest[start..]),
+        };
+        let reference = &rest[start + 2..end];
+        let (name, default) = match reference.find(":-") {
+            Some(separator) => (&reference[..separator], Some(&reference[separator + 2..])),
+            None => (reference, None),
+        };
+        if name.is_empty() {
+            bail!("empty reference ${{{}}}", reference);
+        }
+
+        let value = lookup(name)?.filter(|value| !value.is_empty());
+        match (value, default) {
+            (Some(value), _) if quotes.in_string() => result.push_str(&escape(&value)?),
+            (Some(value), _) => {
+                result.push_str(&value);
+                quotes.scan(&value);
+            }
+            // Defaults are part of the configuration, written the way it needs them
+            (None, Some(default)) => {
+                result.push_str(default);
+                quotes.scan(default);
+            }
+            (None, None) => bail!("{} is not set and ${{{}}} has no default", name, name),
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::interpolation::interpolate_with;
+
+    fn lookup(name: &str) -> Result<Option<String>> {
+        Ok(match name {
+            "NAMESPACE" => Some("Production".to_string()),
+            "EMPTY" => Some(String::new()),
+            "ssm:/alerts/token" => Some("secret".to_string()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let content = r#"{"namespace": "${NAMESPACE}", "group": "${GROUP:-logs}", "token": "${ssm:/alerts/token}"}"#;
+        assert_eq!(
+            r#"{"namespace": "Production", "group": "logs", "token": "secret"}"#,
+            interpolate_with(content, lookup).unwrap()
+        );
+        assert_eq!(
+            "default and ${NAMESPACE}",
+            interpolate_with("${EMPTY:-default} and $${NAMESPACE}", lookup).unwrap()
+        );
+        assert!(interpolate_with("${GROUP}", lookup).is_err());
+        assert!(interpolate_with("${NAMESPACE", lookup).is_err());
+    }
+
+    #[test]
+    fn test_escape_values() {
+        let lookup = |name: &str| -> Result<Option<String>> {
+            Ok(match name {
+                "QUOTED" => Some("say \"hi\"\\n".to_string()),
+                "NUMBER" => Some("42".to_string()),
+                _ => None,
+            })
+        };
+        let content = r#"{"message": "${QUOTED}", "prefix": "a \" ${QUOTED}", "limit": ${NUMBER}}"#;
+        let interpolated = interpolate_with(content, lookup).unwrap();
+        assert_eq!(
+            r#"{"message": "say \"hi\"\\n", "prefix": "a \" say \"hi\"\\n", "limit": 42}"#,
+            interpolated
+        );
+        let value: serde_json::Value = serde_json::from_str(&interpolated).unwrap();
+        assert_eq!("say \"hi\"\\n", value["message"]);
+        assert_eq!(42, value["limit"]);
+
+        // Outside of double quotes values are inserted as they are
+        assert_eq!(
+            "token: say \"hi\"\\n",
+            interpolate_with("token: ${QUOTED}", lookup).unwrap()
+        );
+        assert_eq!(
+            r#"{"a": "x", "b": "say \"hi\"\\n"}"#,
+            interpolate_with(r#"{"a": "${MISSING:-x}", "b": "${QUOTED}"}"#, lookup).unwrap()
+        );
+
+        // Single quoted strings have no escapes, and quotes in comments or words are not strings
+        assert_eq!(
+            "token: 'say \"hi\"\\n'",
+            interpolate_with("token: '${QUOTED}'", lookup).unwrap()
+        );
+        assert_eq!(
+            "# a \"quote\nmessage: don't \"say \\\"hi\\\"\\\\n\"",
+            interpolate_with("# a \"quote\nmessage: don't \"${QUOTED}\"", lookup).unwrap()
+        );
+    }
+}
@@ -4,9 +4,16 @@ pub mod output;
 pub mod pipelines;
 pub mod types;
 
+mod checkpoints;
 mod config;
+mod events;
 mod handlers;
+mod interpolation;
+mod pipelines_file;
+mod pipelines_source;
 mod s3;
+mod state;
+mod template;
 
 pub use crate::log_processing::process_log;
 pub use crate::pipelines::{compile_pipelines, Pipeline, Pipelines};
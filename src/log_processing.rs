@@ -1,18 +1,100 @@
-use std::io::Read;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::time::{Duration, Instant};
 
-use anyhow::{Context as _, Result};
-use log::{info, trace};
+use anyhow::{anyhow, Context as _, Result};
+use flate2::bufread::GzDecoder;
+use log::{error, info, trace};
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, Serializer};
 
-use crate::pipelines::Pipeline;
+use crate::pipelines::{CompiledPipelines, RoutingMode};
 use crate::types::RequestLogLine;
 
 const LOG_DELIMITER: u8 = b' ';
 
-#[derive(Debug)]
+#[derive(Debug, Default, Serialize)]
 pub struct ProcessLogOutput {
     pub total_lines: u64,
+    /// Lines matched by at least one pipeline
     pub matched_lines: u64,
+    /// Statistics of each pipeline, in the order they are configured
+    pub pipelines: Vec<PipelineStats>,
+}
+
+impl ProcessLogOutput {
+    /// Adds the counters of another processed file to these ones
+    pub fn merge(&mut self, other: ProcessLogOutput) {
+        self.total_lines += other.total_lines;
+        self.matched_lines += other.matched_lines;
+        if self.pipelines.is_empty() {
+            self.pipelines = other.pipelines;
+            return;
+        }
+        for (stats, other) in self.pipelines.iter_mut().zip(other.pipelines) {
+            stats.matched += other.matched;
+            stats.emitted += other.emitted;
+            stats.dropped += other.dropped;
+            stats.errors += other.errors;
+            stats.output_latency += other.output_latency;
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PipelineStats {
+    pub name: String,
+    /// Lines matched by the filter
+    pub matched: u64,
+    /// Lines sent by an output, counted once per output when the output flushes them
+    pub emitted: u64,
+    /// Lines an output failed to accept or to flush, counted once per output
+    pub dropped: u64,
+    /// Errors returned by the outputs, including the ones flushing at the end of a file
+    pub errors: u64,
+    /// Time spent inside the outputs
+    #[serde(rename = "output_latency_ms", serialize_with = "serialize_millis")]
+    pub output_latency: Duration,
+}
+
+impl PipelineStats {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+
+    /// Counts the lines an output call settled, the ones it sent or failed to send
+    fn record(&mut self, start: Instant, settled: usize, result: Result<()>) -> Result<()> {
+        self.output_latency += start.elapsed();
+        match &result {
+            Ok(()) => self.emitted += settled as u64,
+            Err(error) => {
+                error!("Output of pipeline {} failed: {:?}", self.name, error);
+                self.errors += 1;
+                self.dropped += settled as u64;
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Display for PipelineStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: matched={} emitted={} dropped={} errors={} output_latency={:?}",
+            self.name, self.matched, self.emitted, self.dropped, self.errors, self.output_latency
+        )
+    }
+}
+
+fn serialize_millis<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_u64(duration.as_millis() as u64)
 }
 
 pub(crate) fn parse_log_stream<T, R>(file: R) -> impl Iterator<Item = Result<T>>
@@ -29,43 +111,234 @@ where
         })
 }
 
-pub fn process_log<R>(
-    buffer: R,
-    pipelines: &[(&Pipeline, wirefilter::Filter)],
-) -> Result<ProcessLogOutput>
+/// Where a gzip file stopped being processed: the compressed offset of the member being read
+/// and how many of its lines, and of the whole file, were processed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub member_offset: u64,
+    pub member_line: u64,
+    pub line: u64,
+}
+
+/// Counters of a file being processed
+struct FileProcessing<'a> {
+    pipelines: &'a CompiledPipelines,
+    total_lines: u64,
+    matched_lines: u64,
+    stats: Vec<PipelineStats>,
+    /// First error of an output, returned once the file is finished
+    error: Option<anyhow::Error>,
+}
+
+impl<'a> FileProcessing<'a> {
+    fn new(pipelines: &'a CompiledPipelines) -> Self {
+        let stats = pipelines
+            .iter()
+            .enumerate()
+            .map(|(index, (pipeline, _))| PipelineStats::new(pipeline.display_name(index)))
+            .collect();
+        Self {
+            pipelines,
+            total_lines: 0,
+            matched_lines: 0,
+            stats,
+            error: None,
+        }
+    }
+
+    /// Processes the lines of `buffer` after the first `skip` ones, until the deadline passes.
+    /// Returns how many lines were read, the skipped ones included, and if the deadline stopped it.
+    fn process<R: Read>(
+        &mut self,
+        buffer: R,
+        skip: u64,
+        deadline: Option<Instant>,
+    ) -> Result<(u64, bool)> {
+        let mut buffer = BufReader::new(buffer);
+        let mut read = 0;
+        // Log lines have no quoted line breaks, the skipped ones don't need to be parsed
+        let mut skipped = Vec::new();
+        while read < skip {
+            skipped.clear();
+            if buffer.read_until(b'\n', &mut skipped)? == 0 {
+                return Ok((read, false));
+            }
+            read += 1;
+        }
+        for line in parse_log_stream::<RequestLogLine, _>(buffer) {
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                return Ok((read, true));
+            }
+            read += 1;
+            self.total_lines += 1;
+            match line {
+                Ok(line) => self.process_line(&line)?,
+                Err(error) => trace!("Skipping line because of error {:?}", error),
+            }
+        }
+        Ok((read, false))
+    }
+
+    fn process_line(&mut self, line: &RequestLogLine) -> Result<()> {
+        let context = line.execution_context()?;
+        let mut matched = false;
+        for (index, (pipeline, filter)) in self.pipelines.iter().enumerate() {
+            if !filter.execute(&context).unwrap() {
+                continue;
+            }
+            matched = true;
+            let stats = &mut self.stats[index];
+            stats.matched += 1;
+            for output in pipeline.outputs() {
+                let processor = output.get_log_processor();
+                let start = Instant::now();
+                let buffered = processor.buffered_lines() + 1;
+                let result = processor.process_line(line);
+                let settled = buffered.saturating_sub(processor.buffered_lines());
+                if let Err(error) = stats.record(start, settled, result) {
+                    self.error.get_or_insert(error);
+                }
+            }
+            if pipeline.stop || self.pipelines.mode() == RoutingMode::FirstMatch {
+                break;
+            }
+        }
+        if matched {
+            self.matched_lines += 1;
+        }
+        Ok(())
+    }
+
+    /// Flushes the outputs, so the processed lines are sent before a position is saved. Fails
+    /// with the first error of an output, after logging the counters of the file.
+    fn finish(mut self) -> Result<ProcessLogOutput> {
+        for ((pipeline, _), stats) in self.pipelines.iter().zip(self.stats.iter_mut()) {
+            for output in pipeline.outputs() {
+                let processor = output.get_log_processor();
+                let start = Instant::now();
+                let buffered = processor.buffered_lines();
+                let result = processor.finish();
+                let settled = buffered.saturating_sub(processor.buffered_lines());
+                if let Err(error) = stats.record(start, settled, result) {
+                    self.error.get_or_insert(error);
+                }
+            }
+        }
+        if let Some(error) = self.error {
+            let pipelines: Vec<String> = self.stats.iter().map(ToString::to_string).collect();
+            error!(
+                "Outputs failed after {} lines with {} matches [{}]",
+                self.total_lines,
+                self.matched_lines,
+                pipelines.join(", ")
+            );
+            return Err(error.context("an output failed to send the matched lines"));
+        }
+        Ok(ProcessLogOutput {
+            total_lines: self.total_lines,
+            matched_lines: self.matched_lines,
+            pipelines: self.stats,
+        })
+    }
+}
+
+pub fn process_log<R>(buffer: R, pipelines: &CompiledPipelines) -> Result<ProcessLogOutput>
 where
     R: Read,
 {
-    let mut total_lines = 0;
-    let mut matched_lines = 0;
-    let lines = parse_log_stream::<RequestLogLine, _>(buffer);
+    let mut processing = FileProcessing::new(pipelines);
     info!("Processing file");
+    processing.process(buffer, 0, None)?;
+    info!("Processed");
+    processing.finish()
+}
+
+/// Counts the bytes read from a reader
+struct Counting<R> {
+    inner: R,
+    consumed: u64,
+}
+
+impl<R: BufRead> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.consumed += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for Counting<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.consumed += amount as u64;
+        self.inner.consume(amount)
+    }
+}
+
+/// Processes a gzip file, made of one or more members, from `position`: `compressed` starts at
+/// the member it points to. Lines must not span members. Returns where the file stopped when the
+/// deadline passed before its end.
+pub(crate) fn process_gzip_log<R>(
+    compressed: R,
+    mut position: Position,
+    pipelines: &CompiledPipelines,
+    deadline: Option<Instant>,
+) -> Result<(ProcessLogOutput, Option<Position>)>
+where
+    R: Read,
+{
+    let mut processing = FileProcessing::new(pipelines);
+    let mut compressed = Counting {
+        inner: BufReader::new(compressed),
+        consumed: 0,
+    };
+    info!("Processing file from {:?}", position);
 
-    for line in lines
-        .inspect(|_| total_lines += 1)
-        .filter(|line| {
-            if line.is_ok() {
-                return true;
+    let mut process_members = || -> Result<bool> {
+        loop {
+            if compressed.fill_buf()?.is_empty() {
+                return Ok(false);
             }
-            trace!("Skipping line because of error {:?}", line);
-            false
-        })
-        .map(Result::unwrap)
-    {
-        let context = line.execution_context()?;
-        for (pipeline, filter) in pipelines {
-            if filter.execute(&context).unwrap() {
-                pipeline.output.get_log_processor().process_line(&line)?;
-                matched_lines += 1;
+            let start = compressed.consumed;
+            let member = GzDecoder::new(&mut compressed);
+            let (read, stopped) = processing.process(member, position.member_line, deadline)?;
+            position.line += read.checked_sub(position.member_line).ok_or_else(|| {
+                anyhow!(
+                    "the member at byte {} has {} lines, fewer than the {} to skip",
+                    position.member_offset,
+                    read,
+                    position.member_line
+                )
+            })?;
+            if stopped {
+                position.member_line = read;
+                return Ok(true);
             }
+            position.member_offset += compressed.consumed - start;
+            position.member_line = 0;
         }
-    }
+    };
+    let stopped = match process_members() {
+        Ok(stopped) => stopped,
+        Err(error) => {
+            // Sent now, while the file is still leased, the handler's own flush would send them
+            // again after the lease is released and another invocation started over
+            if let Err(error) = processing.finish() {
+                error!("Failed to flush the lines of a failed file {:?}", error);
+            }
+            return Err(error);
+        }
+    };
 
-    info!("Processed");
-    Ok(ProcessLogOutput {
-        total_lines,
-        matched_lines,
-    })
+    info!("Processed up to line {}", position.line);
+    // Fails when the outputs can't flush, so no position is saved for lines that weren't sent
+    Ok((
+        processing.finish()?,
+        if stopped { Some(position) } else { None },
+    ))
 }
 
 pub(crate) fn csv_writer_builder() -> csv::WriterBuilder {
@@ -80,19 +353,46 @@ pub(crate) fn csv_reader_builder() -> csv::ReaderBuilder {
     reader
 }
 
+/// Log lines shared by the tests of the outputs
 #[cfg(test)]
-mod tests {
+pub(crate) mod fixtures {
     use std::io::Cursor;
 
+    use crate::log_processing::parse_log_stream;
+    use crate::types::RequestLogLine;
+
+    pub(crate) const GOOD_LOGS: &str = include_str!("../tests/fixtures/logs.txt");
+
+    pub(crate) fn log_lines() -> Vec<RequestLogLine> {
+        parse_log_stream(Cursor::new(GOOD_LOGS))
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    pub(crate) fn first_log_line() -> RequestLogLine {
+        log_lines().remove(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+    use std::time::Instant;
+
     use anyhow::Result;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
 
-    use crate::log_processing::{parse_log_stream, process_log};
+    use crate::log_processing::fixtures::GOOD_LOGS;
+    use crate::log_processing::{
+        parse_log_stream, process_gzip_log, process_log, FileProcessing, Position,
+    };
+    use crate::output::http::mock_server;
     use crate::output::void::VoidOutput;
     use crate::output::OutputType;
-    use crate::pipelines::{compile_pipelines, Pipeline, Pipelines};
+    use crate::pipelines::{compile_pipelines, Pipeline, Pipelines, RoutingMode};
     use crate::types::RequestLogLine;
 
-    const GOOD_LOGS: &str = include_str!("../tests/fixtures/logs.txt");
     const BAD_LOGS: &str = include_str!("../tests/fixtures/bad_logs.txt");
 
     fn parse_logs(csv_data: &str) -> Vec<RequestLogLine> {
@@ -115,11 +415,223 @@ mod tests {
     fn test_process_log() {
         let raw_pipelines = Pipelines::new(vec![Pipeline {
             filter: "elb_status_code == 200 && user_agent matches \"(Android|axios)\"".to_string(),
-            output: OutputType::Void(VoidOutput),
+            name: None,
+            output: Some(OutputType::Void(VoidOutput)),
+            outputs: vec![],
+            stop: false,
         }]);
-        let pipelines = compile_pipelines(&raw_pipelines);
+        let pipelines = compile_pipelines(raw_pipelines);
         let result = process_log(Cursor::new(GOOD_LOGS), &pipelines).unwrap();
         assert_eq!(6, result.matched_lines);
         assert_eq!(10, result.total_lines);
     }
+
+    #[test]
+    fn test_parse_pipelines_with_multiple_outputs() {
+        let pipelines: Pipelines = r#"[
+            {"filter": "elb_status_code >= 500", "output": {"type": "void"}},
+            {"filter": "elb_status_code >= 500", "outputs": [{"type": "void"}, {"type": "void"}]},
+            {"filter": "elb_status_code >= 500", "output": {"type": "void"}, "outputs": [{"type": "void"}]}
+        ]"#
+        .parse()
+        .unwrap();
+        let outputs: Vec<usize> = pipelines
+            .inner()
+            .iter()
+            .map(|pipeline| pipeline.outputs().count())
+            .collect();
+        assert_eq!(vec![1, 2, 2], outputs);
+
+        assert!(r#"[{"filter": "elb_status_code >= 500"}]"#.parse::<Pipelines>().is_err());
+    }
+
+    const ROUTING_PIPELINES: &str = r#"[
+        {"filter": "elb_status_code == 200 && user_agent matches \"axios\"", "output": {"type": "void"}},
+        {"filter": "elb_status_code == 200 && user_agent matches \"Android\"", "output": {"type": "void"}, "stop": true},
+        {"filter": "elb_status_code > 0", "output": {"type": "void"}}
+    ]"#;
+
+    #[test]
+    fn test_process_log_routing() {
+        let raw_pipelines: Pipelines = ROUTING_PIPELINES.parse().unwrap();
+        let pipelines = compile_pipelines(raw_pipelines);
+        let result = process_log(Cursor::new(GOOD_LOGS), &pipelines).unwrap();
+        let android = result.pipelines[1].matched;
+        assert_eq!(10, result.matched_lines);
+        assert_eq!(10 - android, result.pipelines[2].matched);
+
+        let raw_pipelines = ROUTING_PIPELINES
+            .parse::<Pipelines>()
+            .unwrap()
+            .with_mode(RoutingMode::FirstMatch);
+        let pipelines = compile_pipelines(raw_pipelines);
+        let result = process_log(Cursor::new(GOOD_LOGS), &pipelines).unwrap();
+        assert_eq!(10, result.matched_lines);
+        let matches: u64 = result.pipelines.iter().map(|stats| stats.matched).sum();
+        assert_eq!(10, matches);
+    }
+
+    #[test]
+    fn test_pipeline_stats() {
+        let raw_pipelines: Pipelines = r#"[
+            {"name": "axios", "filter": "user_agent matches \"axios\"", "outputs": [{"type": "void"}, {"type": "void"}]},
+            {"filter": "elb_status_code > 0", "output": {"type": "void"}}
+        ]"#
+        .parse()
+        .unwrap();
+        let pipelines = compile_pipelines(raw_pipelines);
+        let result = process_log(Cursor::new(GOOD_LOGS), &pipelines).unwrap();
+        let axios = &result.pipelines[0];
+        assert_eq!("axios", axios.name);
+        assert_eq!(axios.matched * 2, axios.emitted);
+        assert_eq!(0, axios.dropped + axios.errors);
+        assert_eq!("#1", result.pipelines[1].name);
+        assert_eq!(10, result.pipelines[1].emitted);
+    }
+
+    #[test]
+    fn test_output_errors() {
+        let raw_pipelines: Pipelines = r#"[{
+            "filter": "elb_status_code > 0",
+            "outputs": [{"type": "statsd", "address": "nowhere", "metric_name": "alb"}, {"type": "void"}]
+        }]"#
+        .parse()
+        .unwrap();
+        let pipelines = compile_pipelines(raw_pipelines);
+        let mut processing = FileProcessing::new(&pipelines);
+        processing
+            .process(Cursor::new(GOOD_LOGS.repeat(3)), 0, None)
+            .unwrap();
+
+        // Statsd sends 20 lines at once, the failed flush drops them with the line that caused it
+        let stats = &processing.stats[0];
+        assert_eq!(30, stats.matched);
+        assert_eq!(30, stats.emitted);
+        assert_eq!(21, stats.dropped);
+        assert_eq!(1, stats.errors);
+        assert!(processing.error.is_some());
+        assert!(processing.finish().is_err());
+
+        assert!(process_log(Cursor::new(GOOD_LOGS), &pipelines).is_err());
+    }
+
+    #[test]
+    fn test_parse_pipelines_with_mode() {
+        let pipelines: Pipelines =
+            r#"{"mode": "first_match", "pipelines": [{"filter": "elb_status_code > 0", "output": {"type": "void"}}]}"#
+                .parse()
+                .unwrap();
+        assert_eq!(RoutingMode::FirstMatch, pipelines.mode());
+        assert_eq!(1, pipelines.inner().len());
+    }
+
+    fn gzip(content: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_process_gzip_log() {
+        let raw_pipelines: Pipelines =
+            r#"[{"filter": "elb_status_code > 0", "output": {"type": "void"}}]"#
+                .parse()
+                .unwrap();
+        let pipelines = compile_pipelines(raw_pipelines);
+        let lines: Vec<&str> = GOOD_LOGS.lines().collect();
+        let first = gzip(&(lines[..4].join("\n") + "\n"));
+        let second = gzip(&(lines[4..].join("\n") + "\n"));
+        let file = [first.clone(), second].concat();
+
+        let (result, stopped) =
+            process_gzip_log(Cursor::new(&file), Position::default(), &pipelines, None).unwrap();
+        assert_eq!(10, result.total_lines);
+        assert_eq!(None, stopped);
+
+        let deadline = Some(Instant::now());
+        let (result, stopped) = process_gzip_log(
+            Cursor::new(&file),
+            Position::default(),
+            &pipelines,
+            deadline,
+        )
+        .unwrap();
+        assert_eq!(0, result.total_lines);
+        assert_eq!(Some(Position::default()), stopped);
+
+        let position = Position {
+            member_offset: 0,
+            member_line: 2,
+            line: 2,
+        };
+        let (result, _) = process_gzip_log(Cursor::new(&file), position, &pipelines, None).unwrap();
+        assert_eq!(8, result.total_lines);
+
+        // A resumed download starts at the member the position points to
+        let position = Position {
+            member_offset: first.len() as u64,
+            member_line: 2,
+            line: 6,
+        };
+        let (result, _) = process_gzip_log(
+            Cursor::new(&file[first.len()..]),
+            position,
+            &pipelines,
+            None,
+        )
+        .unwrap();
+        assert_eq!(4, result.total_lines);
+
+        // Skipped lines are not parsed, an unclosed quote in them doesn't swallow the next ones
+        let broken = gzip(&format!("\"broken\n{}\n", lines[..2].join("\n")));
+        let position = Position {
+            member_offset: 0,
+            member_line: 1,
+            line: 1,
+        };
+        let (result, _) =
+            process_gzip_log(Cursor::new(&broken), position, &pipelines, None).unwrap();
+        assert_eq!(2, result.total_lines);
+
+        // The file was replaced by a shorter one with the same ETag, or the position is corrupted
+        let position = Position {
+            member_offset: 0,
+            member_line: 5,
+            line: 5,
+        };
+        assert!(process_gzip_log(Cursor::new(&first), position, &pipelines, None).is_err());
+    }
+
+    /// Fails every read, like a download interrupted between two members
+    struct Interrupted;
+
+    impl Read for Interrupted {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "interrupted",
+            ))
+        }
+    }
+
+    #[test]
+    fn test_process_gzip_log_flushes_on_error() {
+        let (url, bodies) = mock_server(vec![(200, "")]);
+        let raw_pipelines: Pipelines = format!(
+            r#"[{{"filter": "elb_status_code > 0", "output": {{"type": "http", "url": "{}"}}}}]"#,
+            url
+        )
+        .parse()
+        .unwrap();
+        let pipelines = compile_pipelines(raw_pipelines);
+        let lines: Vec<&str> = GOOD_LOGS.lines().collect();
+        let first = gzip(&(lines[..4].join("\n") + "\n"));
+
+        let file = Cursor::new(first).chain(Interrupted);
+        assert!(process_gzip_log(file, Position::default(), &pipelines, None).is_err());
+        // The lines of the first member were sent before the error was returned
+        let body: Vec<serde_json::Value> =
+            serde_json::from_slice(&bodies.try_recv().unwrap()).unwrap();
+        assert_eq!(4, body.len());
+    }
 }
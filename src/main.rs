@@ -1,15 +1,15 @@
 use std::env;
 use std::env::var_os;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use env_logger::DEFAULT_FILTER_ENV;
-use lambda_runtime::lambda;
 use log::info;
 
-use crate::handlers::handler;
-use crate::log_processing::process_log;
-use crate::pipelines::compile_pipelines;
-use crate::s3::open_s3_file;
+use crate::config::Command;
+use crate::events::S3Object;
+use crate::handlers::{handler, process_object};
+use crate::pipelines_file::json_schema;
+use crate::state::State;
 
 pub mod error;
 pub mod log_processing;
@@ -17,9 +17,16 @@ pub mod output;
 pub mod pipelines;
 pub mod types;
 
+mod checkpoints;
 mod config;
+mod events;
 mod handlers;
+mod interpolation;
+mod pipelines_file;
+mod pipelines_source;
 mod s3;
+mod state;
+mod template;
 
 fn main() -> Result<()> {
     if env::var_os(DEFAULT_FILTER_ENV).is_none() {
@@ -31,19 +38,33 @@ fn main() -> Result<()> {
         env_logger::init();
     }
 
-    // Keep this here so the lambdas can bre pre-validated before they are actually executed
     let config = config::from_args();
-    let pipelines = compile_pipelines(&config.pipelines);
-    info!("Configured pipelines: {:#?}", config.pipelines);
+    if let Some(Command::Schema) = config.command {
+        println!("{}", serde_json::to_string_pretty(&json_schema())?);
+        return Ok(());
+    }
+
+    // Keep this here so the lambdas can bre pre-validated before they are actually executed
+    let mut state = State::new(config)?;
+    info!("Configured pipelines: {:#?}", state.pipelines().raw());
 
     if var_os("INSIDE_LAMBDA").is_some() {
-        lambda!(handler);
+        lambda_runtime::start(
+            move |event, context| handler(event, context, &mut state),
+            None,
+        );
     } else {
-        for bucket_key in &config.bucket_keys {
-            process_log(
-                open_s3_file(&config.bucket_name, &bucket_key, &config)?,
-                &pipelines,
-            )?;
+        let bucket_name = state
+            .config
+            .bucket_name
+            .as_ref()
+            .context("a bucket name is required to process bucket keys")?;
+        for bucket_key in &state.config.bucket_keys {
+            let object = S3Object {
+                bucket: bucket_name.clone(),
+                key: bucket_key.clone(),
+            };
+            process_object(&object, &state, None)?;
         }
     }
 
@@ -25,9 +25,10 @@ pub(crate) trait BufferedLogProcessor {
         if self.buffer_is_empty() {
             return Ok(());
         }
-        self.process_log_lines()?;
+        // Failed lines are dropped, outputs retry what they can before returning an error
+        let result = self.process_log_lines();
         self.buffer_clear();
-        Ok(())
+        result
     }
 
     fn is_full(&self) -> bool {
@@ -9,17 +9,20 @@ use rusoto_logs::{
     CloudWatchLogs, CloudWatchLogsClient, CreateLogStreamRequest, InputLogEvent,
     PutLogEventsRequest,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use uuid::Uuid;
 
 use crate::log_processing::csv_writer_builder;
 use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::CachedClient;
 use crate::types::{LogProcessor, RequestLogLine};
 
 const BUFFER_SIZE: usize = 10;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct CloudwatchLogOutput {
     pub group_name: String,
     pub stream_name_prefix: String,
@@ -29,6 +32,8 @@ pub struct CloudwatchLogOutput {
     sequence_token: RefCell<Option<String>>,
     #[serde(skip)]
     aws_region: Region,
+    #[serde(skip)]
+    client: CachedClient<CloudWatchLogsClient>,
     #[serde(skip, default = "Uuid::new_v4")]
     stream_name_suffix: Uuid,
     #[serde(skip)]
@@ -40,6 +45,14 @@ impl LogProcessor for CloudwatchLogOutput {
         self.add_to_queue(&log_line)?;
         Ok(())
     }
+
+    fn finish(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
 }
 
 impl BufferedLogProcessor for CloudwatchLogOutput {
@@ -60,7 +73,9 @@ impl BufferedLogProcessor for CloudwatchLogOutput {
     }
 
     fn process_log_lines(&self) -> Result<()> {
-        let cli = CloudWatchLogsClient::new(self.aws_region.clone());
+        let cli = self
+            .client
+            .get_or_create(|| CloudWatchLogsClient::new(self.aws_region.clone()));
         self.ensure_stream_exists(&cli)?;
 
         let log_events = self
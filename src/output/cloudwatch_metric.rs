@@ -3,15 +3,18 @@ use std::cell::RefCell;
 use anyhow::{Context, Result};
 use rusoto_cloudwatch::{CloudWatch, CloudWatchClient, Dimension, MetricDatum, PutMetricDataInput};
 use rusoto_core::Region;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 
 use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::CachedClient;
 use crate::types::{LogProcessor, RequestLogLine};
 
 const CLOUDWATCH_BATCH_SIZE: usize = 20;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct CloudwatchMetricOutput {
     pub namespace: String,
     pub metric_name: String,
@@ -19,6 +22,8 @@ pub struct CloudwatchMetricOutput {
     buffer: RefCell<SmallVec<[RequestLogLine; CLOUDWATCH_BATCH_SIZE]>>,
     #[serde(skip)]
     aws_region: Region,
+    #[serde(skip)]
+    client: CachedClient<CloudWatchClient>,
 }
 
 #[derive(Debug)]
@@ -32,6 +37,14 @@ impl LogProcessor for CloudwatchMetricOutput {
         self.add_to_queue(&log_line)?;
         Ok(())
     }
+
+    fn finish(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
 }
 
 impl BufferedLogProcessor for CloudwatchMetricOutput {
@@ -63,7 +76,9 @@ impl BufferedLogProcessor for CloudwatchMetricOutput {
                 .context("error converting log line to metric")?,
         };
 
-        let client = CloudWatchClient::new(self.aws_region.clone());
+        let client = self
+            .client
+            .get_or_create(|| CloudWatchClient::new(self.aws_region.clone()));
         let response = client.put_metric_data(input).sync();
         response
             .map_err(|e| {
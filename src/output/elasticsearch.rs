@@ -0,0 +1,315 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::thread::sleep;
+
+use anyhow::{bail, Context, Result};
+use log::{error, warn};
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::http::{http_client, send_with_retries};
+use crate::output::{backoff, deserialize_field_name, MAXIMUM_RETRIES};
+use crate::template;
+use crate::types::{LogProcessor, RequestLogLine};
+
+const BUFFER_SIZE: usize = 500;
+const TIMEOUT_SECONDS: u64 = 30;
+
+/// Indexes matched lines in Elasticsearch or OpenSearch through the `_bulk` API
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ElasticsearchOutput {
+    pub url: String,
+    /// Template for the index name, e.g. `alb-{yyyy.MM.dd}`
+    #[serde(default = "default_index")]
+    pub index: String,
+    /// Field used as the document id, so reprocessing a file does not duplicate documents
+    #[serde(default, deserialize_with = "deserialize_field_name")]
+    pub id_field: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(skip)]
+    buffer: RefCell<Vec<RequestLogLine>>,
+    #[serde(skip)]
+    client: RefCell<Option<Client>>,
+}
+
+fn default_index() -> String {
+    "alb-{yyyy.MM.dd}".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkResponse {
+    errors: bool,
+    items: Vec<HashMap<String, BulkItem>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkItem {
+    status: u16,
+    #[serde(default)]
+    error: Option<serde_json::Value>,
+}
+
+impl BulkItem {
+    fn is_retryable(&self) -> bool {
+        self.status == 429 || self.status >= 500
+    }
+}
+
+impl LogProcessor for ElasticsearchOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        self.add_to_queue(&log_line)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
+}
+
+impl BufferedLogProcessor for ElasticsearchOutput {
+    fn maximum_buffer_size(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    fn buffer_clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+
+    fn push_to_buffer(&self, log_line: RequestLogLine) {
+        self.buffer.borrow_mut().push(log_line);
+    }
+
+    fn process_log_lines(&self) -> Result<()> {
+        let mut pending = self
+            .buffer
+            .borrow()
+            .iter()
+            .map(|line| self.bulk_operation(line))
+            .collect::<Result<Vec<_>>>()
+            .context("error converting log line to a bulk operation")?;
+
+        let mut rejected = 0;
+        for attempt in 0..=MAXIMUM_RETRIES {
+            if attempt > 0 {
+                sleep(backoff(attempt));
+            }
+            let response = self.send_bulk(&pending)?;
+            if !response.errors {
+                pending.clear();
+                break;
+            }
+
+            let mut retryable = vec![];
+            for (operation, item) in pending.into_iter().zip(response.items) {
+                let item = match item.into_iter().next() {
+                    Some((_, item)) => item,
+                    None => continue,
+                };
+                if item.error.is_none() {
+                    continue;
+                }
+                if item.is_retryable() {
+                    retryable.push(operation);
+                } else {
+                    error!("Document rejected by {}: {:?}", self.url, item.error);
+                    rejected += 1;
+                }
+            }
+            pending = retryable;
+            if pending.is_empty() {
+                break;
+            }
+            warn!("{} documents failed, retrying", pending.len());
+        }
+        if !pending.is_empty() {
+            bail!(
+                "{} documents could not be indexed and {} were rejected by {}",
+                pending.len(),
+                rejected,
+                self.url
+            );
+        }
+        if rejected > 0 {
+            bail!("{} documents were rejected by {}", rejected, self.url);
+        }
+        Ok(())
+    }
+}
+
+impl ElasticsearchOutput {
+    /// Renders the action and source lines of a single document
+    fn bulk_operation(&self, line: &RequestLogLine) -> Result<String> {
+        let mut action = json!({ "_index": template::render(&self.index, line)? });
+        if let Some(field) = &self.id_field {
+            let id = line
+                .field_value(field)
+                .with_context(|| format!("unknown id field {}", field))?;
+            // Missing values are logged as `-`, these documents get an id from the cluster
+            if !id.is_empty() && id != "-" {
+                action["_id"] = id.into();
+            }
+        }
+        Ok(format!(
+            "{}\n{}\n",
+            json!({ "index": action }),
+            serde_json::to_string(line)?
+        ))
+    }
+
+    fn send_bulk(&self, operations: &[String]) -> Result<BulkResponse> {
+        let client = self.client()?;
+        let url = format!("{}/_bulk", self.url.trim_end_matches('/'));
+        let body = operations.concat();
+        let response = send_with_retries(&url, || {
+            let mut request = client
+                .post(&url)
+                .header(CONTENT_TYPE, "application/x-ndjson")
+                .body(body.clone());
+            for (name, value) in &self.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            request
+        })?;
+        Ok(response.json().context("invalid bulk response")?)
+    }
+
+    fn client(&self) -> Result<Client> {
+        let mut client = self.client.borrow_mut();
+        if client.is_none() {
+            *client = Some(http_client(TIMEOUT_SECONDS)?);
+        }
+        Ok(client.clone().unwrap())
+    }
+}
+
+impl Drop for ElasticsearchOutput {
+    fn drop(&mut self) {
+        self.flush().expect("failed to flush documents");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::{first_log_line, log_lines};
+    use crate::output::buffered_trait::BufferedLogProcessor;
+    use crate::output::elasticsearch::ElasticsearchOutput;
+    use crate::output::http::mock_server;
+    use crate::types::LogProcessor;
+
+    #[test]
+    fn test_bulk_operation() {
+        let output: ElasticsearchOutput = serde_json::from_value(json!({
+            "url": "http://localhost:9200",
+            "index": "alb-{yyyy.MM.dd}",
+            "id_field": "trace_id",
+        }))
+        .unwrap();
+        let mut line = first_log_line();
+
+        let operation = output.bulk_operation(&line).unwrap();
+        assert!(operation.ends_with('\n'));
+        let documents: Vec<serde_json::Value> = operation
+            .lines()
+            .map(|document| serde_json::from_str(document).unwrap())
+            .collect();
+        assert_eq!(2, documents.len());
+        assert_eq!("alb-2020.02.12", documents[0]["index"]["_index"]);
+        assert_eq!(line.trace_id, documents[0]["index"]["_id"]);
+        assert_eq!(line.trace_id, documents[1]["trace_id"]);
+
+        for missing in &["-", ""] {
+            line.trace_id = missing.to_string();
+            let operation = output.bulk_operation(&line).unwrap();
+            let action: serde_json::Value =
+                serde_json::from_str(operation.lines().next().unwrap()).unwrap();
+            assert!(action["index"].get("_id").is_none());
+        }
+    }
+
+    #[test]
+    fn test_rejected_documents() {
+        let (url, bodies) = mock_server(vec![
+            (
+                200,
+                r#"{"errors": true, "items": [
+                    {"index": {"status": 201}},
+                    {"index": {"status": 400, "error": {"type": "mapper_parsing_exception"}}},
+                    {"index": {"status": 429, "error": {"type": "es_rejected_execution_exception"}}}
+                ]}"#,
+            ),
+            (
+                200,
+                r#"{"errors": false, "items": [{"index": {"status": 201}}]}"#,
+            ),
+        ]);
+        let output: ElasticsearchOutput = serde_json::from_value(json!({ "url": url })).unwrap();
+        for line in log_lines().iter().take(3) {
+            output.process_line(line).unwrap();
+        }
+
+        let error = output.flush().unwrap_err();
+        assert!(error.to_string().starts_with("1 documents were rejected"));
+        bodies.recv().unwrap();
+        // Only the throttled document is sent again
+        let retried = String::from_utf8(bodies.recv().unwrap()).unwrap();
+        assert_eq!(2, retried.lines().count());
+    }
+
+    #[test]
+    fn test_unknown_id_field() {
+        let output = serde_json::from_value::<ElasticsearchOutput>(json!({
+            "url": "http://localhost:9200",
+            "id_field": "request_id",
+        }));
+        assert!(output.unwrap_err().to_string().contains("request_id"));
+    }
+
+    /// Needs a local OpenSearch, e.g.
+    /// `docker run -p 9200:9200 -e discovery.type=single-node -e plugins.security.disabled=true opensearchproject/opensearch`
+    #[test]
+    #[ignore]
+    fn test_bulk_indexing() {
+        let url = env::var("OPENSEARCH_URL").unwrap_or_else(|_| "http://localhost:9200".into());
+        let output: ElasticsearchOutput = serde_json::from_value(json!({
+            "url": url,
+            "index": "alb-test-{yyyy.MM.dd}",
+            "id_field": "trace_id",
+        }))
+        .unwrap();
+
+        for line in log_lines() {
+            output.process_line(&line).unwrap();
+        }
+        output.flush().unwrap();
+        // Indexing the same lines again only overwrites the documents
+        for line in log_lines() {
+            output.process_line(&line).unwrap();
+        }
+        output.flush().unwrap();
+
+        let count: serde_json::Value = reqwest::blocking::Client::new()
+            .post(&format!("{}/alb-test-*/_refresh", url))
+            .send()
+            .and_then(|_| reqwest::blocking::get(&format!("{}/alb-test-*/_count", url))?.json())
+            .unwrap();
+        assert_eq!(10, count["count"]);
+    }
+}
@@ -0,0 +1,157 @@
+use std::cell::RefCell;
+use std::thread::sleep;
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use log::warn;
+use rusoto_core::{Region, RusotoError};
+use rusoto_firehose::{
+    KinesisFirehose, KinesisFirehoseClient, PutRecordBatchError, PutRecordBatchInput, Record,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::format::LineFormat;
+use crate::output::{backoff, region_with_endpoint, split_batches, CachedClient, MAXIMUM_RETRIES};
+use crate::types::{LogProcessor, RequestLogLine};
+
+/// Firehose accepts at most 500 records or 4MB per PutRecordBatch call
+const BUFFER_SIZE: usize = 500;
+const MAXIMUM_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct FirehoseOutput {
+    pub delivery_stream_name: String,
+    #[serde(default)]
+    pub format: LineFormat,
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(skip)]
+    buffer: RefCell<Vec<RequestLogLine>>,
+    #[serde(skip)]
+    aws_region: Region,
+    #[serde(skip)]
+    client: CachedClient<KinesisFirehoseClient>,
+}
+
+impl LogProcessor for FirehoseOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        self.add_to_queue(&log_line)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
+}
+
+impl BufferedLogProcessor for FirehoseOutput {
+    fn maximum_buffer_size(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    fn buffer_clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+
+    fn push_to_buffer(&self, log_line: RequestLogLine) {
+        self.buffer.borrow_mut().push(log_line);
+    }
+
+    fn process_log_lines(&self) -> Result<()> {
+        // Firehose concatenates records on delivery, so each one carries its own line break
+        let records = self
+            .buffer
+            .borrow()
+            .iter()
+            .map(|line| {
+                let mut data = self.format.encode(line)?;
+                data.push(b'\n');
+                Ok(Record {
+                    data: Bytes::from(data),
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("error converting log line to firehose record")?;
+
+        let client = self.client.get_or_create(|| {
+            KinesisFirehoseClient::new(region_with_endpoint(&self.aws_region, &self.endpoint))
+        });
+        for batch in split_batches(records, BUFFER_SIZE, MAXIMUM_BATCH_BYTES, |record| {
+            record.data.len()
+        }) {
+            self.put_record_batch(&client, batch)?;
+        }
+        Ok(())
+    }
+}
+
+impl FirehoseOutput {
+    /// Sends a batch, retrying only the records firehose failed to accept
+    fn put_record_batch(&self, client: &KinesisFirehoseClient, records: Vec<Record>) -> Result<()> {
+        let mut pending = records;
+        for attempt in 0..=MAXIMUM_RETRIES {
+            if attempt > 0 {
+                sleep(backoff(attempt));
+            }
+            let input = PutRecordBatchInput {
+                delivery_stream_name: self.delivery_stream_name.clone(),
+                records: pending.clone(),
+            };
+            let response = match client.put_record_batch(input).sync() {
+                Ok(response) => response,
+                // The whole request was throttled, it is retried like rejected records
+                Err(RusotoError::Service(PutRecordBatchError::ServiceUnavailable(error))) => {
+                    warn!(
+                        "Delivery stream {} throttled {} records, retrying: {}",
+                        self.delivery_stream_name,
+                        pending.len(),
+                        error
+                    );
+                    continue;
+                }
+                Err(error) => {
+                    return Err(error).with_context(|| {
+                        format!("error sending records to {}", self.delivery_stream_name)
+                    })
+                }
+            };
+            if response.failed_put_count == 0 {
+                return Ok(());
+            }
+
+            pending = pending
+                .into_iter()
+                .zip(response.request_responses)
+                .filter(|(_, result)| result.error_code.is_some())
+                .map(|(record, _)| record)
+                .collect();
+            warn!(
+                "{} records were rejected by delivery stream {}, retrying",
+                pending.len(),
+                self.delivery_stream_name
+            );
+        }
+        bail!(
+            "{} records could not be sent to delivery stream {}",
+            pending.len(),
+            self.delivery_stream_name
+        )
+    }
+}
+
+impl Drop for FirehoseOutput {
+    fn drop(&mut self) {
+        self.flush().expect("failed to flush firehose records");
+    }
+}
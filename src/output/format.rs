@@ -0,0 +1,100 @@
+use std::io::{Cursor, Read};
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::log_processing::csv_writer_builder;
+use crate::template;
+use crate::types::RequestLogLine;
+
+/// Encoding used by outputs that send single lines as messages or records
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LineFormat {
+    Json,
+    Csv,
+}
+
+impl Default for LineFormat {
+    fn default() -> Self {
+        LineFormat::Json
+    }
+}
+
+impl LineFormat {
+    /// Encodes a line without any trailing line break
+    pub fn encode(self, line: &RequestLogLine) -> Result<Vec<u8>> {
+        match self {
+            LineFormat::Json => Ok(serde_json::to_vec(line)?),
+            LineFormat::Csv => {
+                let mut buffer = Cursor::new(Vec::new());
+                csv_writer_builder()
+                    .from_writer(buffer.by_ref())
+                    .serialize(line)?;
+                let mut data = buffer.into_inner();
+                while data.last() == Some(&b'\n') || data.last() == Some(&b'\r') {
+                    data.pop();
+                }
+                Ok(data)
+            }
+        }
+    }
+}
+
+/// Builds the JSON body sent by notification outputs: the rendered `message` template (when
+/// configured) and the whole line
+pub(crate) fn notification_body(message: &Option<String>, line: &RequestLogLine) -> Result<String> {
+    let message = match message {
+        Some(message) => Some(template::render(message, line)?),
+        None => None,
+    };
+    Ok(serde_json::to_string(&json!({
+        "message": message,
+        "line": line,
+    }))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::log_processing::fixtures::first_log_line;
+    use crate::log_processing::parse_log_stream;
+    use crate::output::format::{notification_body, LineFormat};
+    use crate::types::RequestLogLine;
+
+    #[test]
+    fn test_encode() {
+        let line = first_log_line();
+        let json: serde_json::Value =
+            serde_json::from_slice(&LineFormat::Json.encode(&line).unwrap()).unwrap();
+        assert_eq!(line.elb_status_code, json["elb_status_code"]);
+        assert_eq!(line.trace_id, json["trace_id"]);
+
+        let csv = LineFormat::Csv.encode(&line).unwrap();
+        assert!(!csv.ends_with(b"\n"));
+        let reparsed: RequestLogLine = parse_log_stream(Cursor::new(csv)).next().unwrap().unwrap();
+        assert_eq!(line.trace_id, reparsed.trace_id);
+        assert_eq!(line.user_agent, reparsed.user_agent);
+    }
+
+    #[test]
+    fn test_notification_body() {
+        let line = first_log_line();
+        let body: serde_json::Value = serde_json::from_str(
+            &notification_body(&Some("{elb_status_code} on {elb_name}".to_string()), &line)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            format!("{} on {}", line.elb_status_code, line.elb_name),
+            body["message"]
+        );
+        assert_eq!(line.trace_id, body["line"]["trace_id"]);
+        let body: serde_json::Value =
+            serde_json::from_str(&notification_body(&None, &line).unwrap()).unwrap();
+        assert!(body["message"].is_null());
+    }
+}
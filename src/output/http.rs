@@ -0,0 +1,348 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::thread::sleep;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::warn;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::StatusCode;
+use schemars::JsonSchema;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::{backoff, split_batches, MAXIMUM_RETRIES};
+use crate::types::{LogProcessor, RequestLogLine};
+
+const DEFAULT_BATCH_SIZE: usize = 100;
+const DEFAULT_BATCH_BYTES: usize = 1024 * 1024;
+const DEFAULT_TIMEOUT_SECONDS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpBodyFormat {
+    /// A JSON array with all the lines of a batch
+    Json,
+    /// One JSON document per line
+    Ndjson,
+}
+
+impl Default for HttpBodyFormat {
+    fn default() -> Self {
+        HttpBodyFormat::Json
+    }
+}
+
+/// POSTs batches of matched lines to a webhook
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HttpOutput {
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub format: HttpBodyFormat,
+    #[serde(
+        default = "default_batch_size",
+        deserialize_with = "deserialize_batch_size"
+    )]
+    pub batch_size: usize,
+    #[serde(default = "default_batch_bytes")]
+    pub batch_bytes: usize,
+    #[serde(default)]
+    pub gzip: bool,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    #[serde(skip)]
+    buffer: RefCell<Vec<RequestLogLine>>,
+    #[serde(skip)]
+    client: RefCell<Option<Client>>,
+}
+
+fn default_batch_size() -> usize {
+    DEFAULT_BATCH_SIZE
+}
+
+/// A batch of 0 lines would never be full, buffering the whole file
+fn deserialize_batch_size<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match usize::deserialize(deserializer)? {
+        0 => Err(D::Error::custom("batch_size must be at least 1")),
+        batch_size => Ok(batch_size),
+    }
+}
+
+fn default_batch_bytes() -> usize {
+    DEFAULT_BATCH_BYTES
+}
+
+fn default_timeout_seconds() -> u64 {
+    DEFAULT_TIMEOUT_SECONDS
+}
+
+impl LogProcessor for HttpOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        self.add_to_queue(&log_line)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
+}
+
+impl BufferedLogProcessor for HttpOutput {
+    fn maximum_buffer_size(&self) -> usize {
+        self.batch_size
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    fn buffer_clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+
+    fn push_to_buffer(&self, log_line: RequestLogLine) {
+        self.buffer.borrow_mut().push(log_line);
+    }
+
+    fn process_log_lines(&self) -> Result<()> {
+        let documents = self
+            .buffer
+            .borrow()
+            .iter()
+            .map(serde_json::to_vec)
+            .collect::<Result<Vec<_>, _>>()
+            .context("error converting log line to json")?;
+
+        for batch in split_batches(documents, self.batch_size, self.batch_bytes, Vec::len) {
+            let body = self.encode_body(batch)?;
+            let content_type = match self.format {
+                HttpBodyFormat::Json => "application/json",
+                HttpBodyFormat::Ndjson => "application/x-ndjson",
+            };
+            let client = self.client()?;
+            send_with_retries(&self.url, || {
+                let mut request = client
+                    .post(&self.url)
+                    .header(CONTENT_TYPE, content_type)
+                    .body(body.clone());
+                if self.gzip {
+                    request = request.header(CONTENT_ENCODING, "gzip");
+                }
+                for (name, value) in &self.headers {
+                    request = request.header(name.as_str(), value.as_str());
+                }
+                request
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl HttpOutput {
+    fn encode_body(&self, documents: Vec<Vec<u8>>) -> Result<Vec<u8>> {
+        let body = match self.format {
+            HttpBodyFormat::Json => {
+                let mut body = b"[".to_vec();
+                body.extend(documents.join(&b',').into_iter());
+                body.push(b']');
+                body
+            }
+            HttpBodyFormat::Ndjson => documents
+                .into_iter()
+                .flat_map(|mut document| {
+                    document.push(b'\n');
+                    document
+                })
+                .collect(),
+        };
+
+        if !self.gzip {
+            return Ok(body);
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body)?;
+        Ok(encoder.finish()?)
+    }
+
+    fn client(&self) -> Result<Client> {
+        let mut client = self.client.borrow_mut();
+        if client.is_none() {
+            *client = Some(http_client(self.timeout_seconds)?);
+        }
+        Ok(client.clone().unwrap())
+    }
+}
+
+impl Drop for HttpOutput {
+    fn drop(&mut self) {
+        self.flush().expect("failed to flush http requests");
+    }
+}
+
+pub(crate) fn http_client(timeout_seconds: u64) -> Result<Client> {
+    Ok(Client::builder()
+        .timeout(Duration::from_secs(timeout_seconds))
+        .build()?)
+}
+
+/// Sends a request, retrying with an exponential backoff on connection errors, 5xx and 429s
+pub(crate) fn send_with_retries<F>(url: &str, request: F) -> Result<Response>
+where
+    F: Fn() -> RequestBuilder,
+{
+    for attempt in 0..=MAXIMUM_RETRIES {
+        if attempt > 0 {
+            sleep(backoff(attempt));
+        }
+        let response = match request().send() {
+            Ok(response) => response,
+            Err(error) => {
+                warn!("error sending request to {}: {}", url, error);
+                continue;
+            }
+        };
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            warn!("{} answered with {}, retrying", url, status);
+            continue;
+        }
+        bail!(
+            "{} answered with {}: {}",
+            url,
+            status,
+            response.text().unwrap_or_default()
+        );
+    }
+    bail!("giving up sending request to {}", url)
+}
+
+/// Answers every request with the next status and body, sending the received bodies back to the
+/// test. Shared by the tests of the HTTP based outputs
+#[cfg(test)]
+pub(crate) fn mock_server(
+    responses: Vec<(u16, &'static str)>,
+) -> (String, std::sync::mpsc::Receiver<Vec<u8>>) {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = channel();
+
+    thread::spawn(move || {
+        for (status, response) in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                let header = header.to_lowercase();
+                if let Some(length) = header.strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            sender.send(body).unwrap();
+
+            write!(
+                stream,
+                "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                status,
+                response.len(),
+                response
+            )
+            .unwrap();
+        }
+    });
+
+    (url, receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::log_lines;
+    use crate::output::buffered_trait::BufferedLogProcessor;
+    use crate::output::http::{mock_server, HttpOutput};
+    use crate::types::LogProcessor;
+
+    #[test]
+    fn test_http_output_retries_server_errors() {
+        let (url, bodies) = mock_server(vec![(503, ""), (200, "")]);
+        let output: HttpOutput = serde_json::from_value(json!({
+            "url": url,
+            "format": "ndjson",
+            "batch_size": 5,
+        }))
+        .unwrap();
+
+        for line in log_lines().iter().take(3) {
+            output.process_line(line).unwrap();
+        }
+        output.flush().unwrap();
+
+        let first = bodies.recv().unwrap();
+        let second = bodies.recv().unwrap();
+        assert_eq!(first, second);
+        assert_eq!(3, String::from_utf8(second).unwrap().lines().count());
+    }
+
+    #[test]
+    fn test_http_output_json_batches() {
+        let (url, bodies) = mock_server(vec![(200, ""), (200, "")]);
+        let output: HttpOutput = serde_json::from_value(json!({
+            "url": url,
+            "batch_size": 2,
+        }))
+        .unwrap();
+
+        for line in log_lines().iter().take(3) {
+            output.process_line(line).unwrap();
+        }
+        output.flush().unwrap();
+
+        let first: Vec<serde_json::Value> =
+            serde_json::from_slice(&bodies.recv().unwrap()).unwrap();
+        let second: Vec<serde_json::Value> =
+            serde_json::from_slice(&bodies.recv().unwrap()).unwrap();
+        assert_eq!(2, first.len());
+        assert_eq!(1, second.len());
+    }
+
+    #[test]
+    fn test_zero_batch_size() {
+        let output = serde_json::from_value::<HttpOutput>(json!({
+            "url": "http://localhost/hook",
+            "batch_size": 0,
+        }));
+        assert!(output.unwrap_err().to_string().contains("batch_size"));
+    }
+}
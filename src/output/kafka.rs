@@ -0,0 +1,296 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use kafka::client::{Compression, RequiredAcks};
+use kafka::producer::{ProduceConfirm, Producer, Record};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::format::LineFormat;
+use crate::template;
+use crate::types::{LogProcessor, RequestLogLine};
+
+const BUFFER_SIZE: usize = 500;
+const ACK_TIMEOUT_SECONDS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaCompression {
+    None,
+    Gzip,
+    Snappy,
+}
+
+impl Default for KafkaCompression {
+    fn default() -> Self {
+        KafkaCompression::None
+    }
+}
+
+impl From<KafkaCompression> for Compression {
+    fn from(compression: KafkaCompression) -> Self {
+        match compression {
+            KafkaCompression::None => Compression::NONE,
+            KafkaCompression::Gzip => Compression::GZIP,
+            KafkaCompression::Snappy => Compression::SNAPPY,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaAcks {
+    None,
+    One,
+    All,
+}
+
+impl Default for KafkaAcks {
+    fn default() -> Self {
+        KafkaAcks::One
+    }
+}
+
+impl From<KafkaAcks> for RequiredAcks {
+    fn from(acks: KafkaAcks) -> Self {
+        match acks {
+            KafkaAcks::None => RequiredAcks::None,
+            KafkaAcks::One => RequiredAcks::One,
+            KafkaAcks::All => RequiredAcks::All,
+        }
+    }
+}
+
+/// Produces matched lines to a Kafka topic. Buffered records are sent when a log file finishes,
+/// so nothing is left behind once the lambda handler returns.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaOutput {
+    pub brokers: Vec<String>,
+    pub topic: String,
+    /// Template used to render the record key, e.g. `{domain_name}`
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub format: LineFormat,
+    #[serde(default)]
+    pub compression: KafkaCompression,
+    #[serde(default)]
+    pub acks: KafkaAcks,
+    #[serde(skip)]
+    buffer: RefCell<Vec<RequestLogLine>>,
+    #[serde(skip)]
+    producer: RefCell<Option<KafkaProducer>>,
+}
+
+struct KafkaProducer(Producer);
+
+impl fmt::Debug for KafkaProducer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KafkaProducer")
+    }
+}
+
+impl LogProcessor for KafkaOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        self.add_to_queue(&log_line)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
+}
+
+impl BufferedLogProcessor for KafkaOutput {
+    fn maximum_buffer_size(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    fn buffer_clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+
+    fn push_to_buffer(&self, log_line: RequestLogLine) {
+        self.buffer.borrow_mut().push(log_line);
+    }
+
+    fn process_log_lines(&self) -> Result<()> {
+        let messages = self
+            .messages()
+            .context("error converting log line to kafka record")?;
+        let records: Vec<_> = messages
+            .iter()
+            .map(|(key, value)| Record::from_key_value(&self.topic, key.as_bytes(), &value[..]))
+            .collect();
+
+        let mut producer = self.producer.borrow_mut();
+        if producer.is_none() {
+            *producer = Some(KafkaProducer(self.create_producer()?));
+        }
+        let KafkaProducer(producer) = producer.as_mut().unwrap();
+        let confirms = producer
+            .send_all(&records)
+            .with_context(|| format!("error sending records to kafka topic {}", self.topic))?;
+        check_confirms(&confirms)
+    }
+}
+
+impl KafkaOutput {
+    /// Renders the key and encodes the value of every buffered line
+    fn messages(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        self.buffer
+            .borrow()
+            .iter()
+            .map(|line| {
+                let key = match &self.key {
+                    Some(key) => template::render(key, line)?,
+                    None => String::new(),
+                };
+                Ok((key, self.format.encode(line)?))
+            })
+            .collect()
+    }
+
+    fn create_producer(&self) -> Result<Producer> {
+        Producer::from_hosts(self.brokers.clone())
+            .with_ack_timeout(Duration::from_secs(ACK_TIMEOUT_SECONDS))
+            .with_required_acks(self.acks.into())
+            .with_compression(self.compression.into())
+            .create()
+            .with_context(|| format!("failed to connect to kafka brokers {:?}", self.brokers))
+    }
+}
+
+/// Fails when a partition did not accept its records. The partitions records were sent to are
+/// not known, so they are not retried one by one
+fn check_confirms(confirms: &[ProduceConfirm]) -> Result<()> {
+    let failed = confirms
+        .iter()
+        .flat_map(|confirm| {
+            confirm
+                .partition_confirms
+                .iter()
+                .filter_map(move |partition| match &partition.offset {
+                    Ok(_) => None,
+                    Err(code) => Some(format!(
+                        "{}/{}: {:?}",
+                        confirm.topic, partition.partition, code
+                    )),
+                })
+        })
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        bail!("kafka rejected records on {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+impl Drop for KafkaOutput {
+    fn drop(&mut self) {
+        self.flush().expect("failed to flush kafka records");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use kafka::error::KafkaCode;
+    use kafka::producer::{ProduceConfirm, ProducePartitionConfirm};
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::{first_log_line, log_lines};
+    use crate::output::buffered_trait::BufferedLogProcessor;
+    use crate::output::kafka::{check_confirms, KafkaOutput};
+    use crate::types::LogProcessor;
+
+    #[test]
+    fn test_messages() {
+        let output: KafkaOutput = serde_json::from_value(json!({
+            "brokers": ["localhost:9092"],
+            "topic": "alb-logs",
+            "key": "{domain_name}/{elb_status_code}",
+        }))
+        .unwrap();
+        let line = first_log_line();
+        output.push_to_buffer(line.clone());
+
+        let messages = output.messages().unwrap();
+        assert_eq!(1, messages.len());
+        let (key, value) = &messages[0];
+        assert_eq!("internal-service.grover.com/200", key);
+        let value: serde_json::Value = serde_json::from_slice(value).unwrap();
+        assert_eq!(line.trace_id, value["trace_id"]);
+        output.buffer_clear();
+
+        // Without a template records have an empty key
+        let output: KafkaOutput = serde_json::from_value(json!({
+            "brokers": ["localhost:9092"],
+            "topic": "alb-logs",
+            "format": "csv",
+        }))
+        .unwrap();
+        output.push_to_buffer(line);
+        let (key, value) = output.messages().unwrap().remove(0);
+        assert!(key.is_empty());
+        assert!(String::from_utf8(value)
+            .unwrap()
+            .starts_with("https 2020-02-12T04:24:34"));
+        output.buffer_clear();
+    }
+
+    #[test]
+    fn test_check_confirms() {
+        let confirm = |offset| ProduceConfirm {
+            topic: "alb-logs".to_string(),
+            partition_confirms: vec![
+                ProducePartitionConfirm {
+                    offset: Ok(10),
+                    partition: 0,
+                },
+                ProducePartitionConfirm {
+                    offset,
+                    partition: 1,
+                },
+            ],
+        };
+        assert!(check_confirms(&[confirm(Ok(3))]).is_ok());
+        let error = check_confirms(&[confirm(Err(KafkaCode::NotLeaderForPartition))]).unwrap_err();
+        assert_eq!(
+            "kafka rejected records on alb-logs/1: NotLeaderForPartition",
+            error.to_string()
+        );
+    }
+
+    /// Needs a local single node broker, e.g. `docker run -p 9092:9092 apache/kafka`
+    #[test]
+    #[ignore]
+    fn test_produce_records() {
+        let brokers = env::var("KAFKA_BROKERS").unwrap_or_else(|_| "localhost:9092".into());
+        let output: KafkaOutput = serde_json::from_value(json!({
+            "brokers": brokers.split(',').collect::<Vec<_>>(),
+            "topic": "alb-logs-test",
+            "key": "{domain_name}",
+            "compression": "gzip",
+            "acks": "all",
+        }))
+        .unwrap();
+
+        for line in log_lines() {
+            output.process_line(&line).unwrap();
+        }
+        output.finish().unwrap();
+    }
+}
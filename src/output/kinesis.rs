@@ -0,0 +1,170 @@
+use std::cell::RefCell;
+use std::thread::sleep;
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use log::warn;
+use rusoto_core::{Region, RusotoError};
+use rusoto_kinesis::{
+    Kinesis, KinesisClient, PutRecordsError, PutRecordsInput, PutRecordsRequestEntry,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::format::LineFormat;
+use crate::output::{backoff, region_with_endpoint, split_batches, CachedClient, MAXIMUM_RETRIES};
+use crate::template;
+use crate::types::{LogProcessor, RequestLogLine};
+
+/// Kinesis accepts at most 500 records or 5MB per PutRecords call
+const BUFFER_SIZE: usize = 500;
+const MAXIMUM_BATCH_BYTES: usize = 5 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct KinesisOutput {
+    pub stream_name: String,
+    /// Template used to render the partition key of every record
+    #[serde(default = "default_partition_key")]
+    pub partition_key: String,
+    #[serde(default)]
+    pub format: LineFormat,
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(skip)]
+    buffer: RefCell<Vec<RequestLogLine>>,
+    #[serde(skip)]
+    aws_region: Region,
+    #[serde(skip)]
+    client: CachedClient<KinesisClient>,
+}
+
+fn default_partition_key() -> String {
+    "{elb_name}".to_string()
+}
+
+impl LogProcessor for KinesisOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        self.add_to_queue(&log_line)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
+}
+
+impl BufferedLogProcessor for KinesisOutput {
+    fn maximum_buffer_size(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    fn buffer_clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+
+    fn push_to_buffer(&self, log_line: RequestLogLine) {
+        self.buffer.borrow_mut().push(log_line);
+    }
+
+    fn process_log_lines(&self) -> Result<()> {
+        let records = self
+            .buffer
+            .borrow()
+            .iter()
+            .map(|line| {
+                Ok(PutRecordsRequestEntry {
+                    data: Bytes::from(self.format.encode(line)?),
+                    partition_key: template::render(&self.partition_key, line)?,
+                    explicit_hash_key: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("error converting log line to kinesis record")?;
+
+        let client = self.client.get_or_create(|| {
+            KinesisClient::new(region_with_endpoint(&self.aws_region, &self.endpoint))
+        });
+        for batch in split_batches(records, BUFFER_SIZE, MAXIMUM_BATCH_BYTES, |record| {
+            record.data.len() + record.partition_key.len()
+        }) {
+            self.put_records(&client, batch)?;
+        }
+        Ok(())
+    }
+}
+
+impl KinesisOutput {
+    /// Sends a batch, retrying only the records kinesis failed to accept
+    fn put_records(
+        &self,
+        client: &KinesisClient,
+        records: Vec<PutRecordsRequestEntry>,
+    ) -> Result<()> {
+        let mut pending = records;
+        for attempt in 0..=MAXIMUM_RETRIES {
+            if attempt > 0 {
+                sleep(backoff(attempt));
+            }
+            let input = PutRecordsInput {
+                records: pending.clone(),
+                stream_name: self.stream_name.clone(),
+            };
+            let response = match client.put_records(input).sync() {
+                Ok(response) => response,
+                // The whole request was throttled, it is retried like rejected records
+                Err(RusotoError::Service(PutRecordsError::ProvisionedThroughputExceeded(
+                    error,
+                )))
+                | Err(RusotoError::Service(PutRecordsError::KMSThrottling(error))) => {
+                    warn!(
+                        "Kinesis stream {} throttled {} records, retrying: {}",
+                        self.stream_name,
+                        pending.len(),
+                        error
+                    );
+                    continue;
+                }
+                Err(error) => {
+                    return Err(error)
+                        .with_context(|| format!("error sending records to {}", self.stream_name))
+                }
+            };
+            if response.failed_record_count.unwrap_or(0) == 0 {
+                return Ok(());
+            }
+
+            pending = pending
+                .into_iter()
+                .zip(response.records)
+                .filter(|(_, result)| result.error_code.is_some())
+                .map(|(record, _)| record)
+                .collect();
+            warn!(
+                "{} records were rejected by kinesis stream {}, retrying",
+                pending.len(),
+                self.stream_name
+            );
+        }
+        bail!(
+            "{} records could not be sent to kinesis stream {}",
+            pending.len(),
+            self.stream_name
+        )
+    }
+}
+
+impl Drop for KinesisOutput {
+    fn drop(&mut self) {
+        self.flush().expect("failed to flush kinesis records");
+    }
+}
@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::format::LineFormat;
+use crate::output::http::{http_client, send_with_retries};
+use crate::types::{LogProcessor, RequestLogLine};
+
+/// Entries sent per push request
+const BUFFER_SIZE: usize = 1000;
+const TIMEOUT_SECONDS: u64 = 10;
+
+/// Pushes matched lines to Grafana Loki, one stream per combination of label values
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LokiOutput {
+    /// Base url of Loki, `/loki/api/v1/push` is appended to it
+    pub url: String,
+    /// Fields used as labels, keep them low cardinality
+    #[serde(default = "default_labels")]
+    pub labels: Vec<String>,
+    /// Labels added to every stream, e.g. `{"job": "alb"}`
+    #[serde(default)]
+    pub static_labels: HashMap<String, String>,
+    #[serde(default)]
+    pub format: LineFormat,
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(skip)]
+    buffer: RefCell<Vec<RequestLogLine>>,
+    #[serde(skip)]
+    client: RefCell<Option<Client>>,
+}
+
+fn default_labels() -> Vec<String> {
+    vec![
+        "elb_name".to_string(),
+        "target_group".to_string(),
+        "status_class".to_string(),
+    ]
+}
+
+impl LogProcessor for LokiOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        self.add_to_queue(&log_line)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
+}
+
+impl BufferedLogProcessor for LokiOutput {
+    fn maximum_buffer_size(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    fn buffer_clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+
+    fn push_to_buffer(&self, log_line: RequestLogLine) {
+        self.buffer.borrow_mut().push(log_line);
+    }
+
+    fn process_log_lines(&self) -> Result<()> {
+        let client = self.client()?;
+        let url = format!("{}/loki/api/v1/push", self.url.trim_end_matches('/'));
+        let body = serde_json::to_vec(&self.push_request()?)?;
+        send_with_retries(&url, || {
+            let mut request = client
+                .post(&url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(tenant_id) = &self.tenant_id {
+                request = request.header("X-Scope-OrgID", tenant_id.as_str());
+            }
+            for (name, value) in &self.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            request
+        })?;
+        Ok(())
+    }
+}
+
+impl LokiOutput {
+    /// Builds the push body of the buffered lines, grouped by stream
+    fn push_request(&self) -> Result<serde_json::Value> {
+        let mut streams: BTreeMap<BTreeMap<String, String>, Vec<&RequestLogLine>> = BTreeMap::new();
+        let buffer = self.buffer.borrow();
+        for line in buffer.iter() {
+            streams
+                .entry(self.stream_labels(line)?)
+                .or_default()
+                .push(line);
+        }
+
+        let streams = streams
+            .into_iter()
+            .map(|(labels, mut lines)| {
+                // Loki rejects the entries of a push that are out of order within their stream
+                lines.sort_by_key(|line| line.timestamp);
+                let values = lines
+                    .into_iter()
+                    .map(|line| {
+                        Ok(json!([
+                            line.timestamp.timestamp_nanos().to_string(),
+                            String::from_utf8_lossy(&self.format.encode(line)?),
+                        ]))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(json!({ "stream": labels, "values": values }))
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("error converting log lines to loki streams")?;
+        Ok(json!({ "streams": streams }))
+    }
+
+    fn stream_labels(&self, line: &RequestLogLine) -> Result<BTreeMap<String, String>> {
+        let mut labels: BTreeMap<String, String> = self
+            .static_labels
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        for field in &self.labels {
+            let value = line
+                .field_value(field)
+                .with_context(|| format!("unknown label field {}", field))?;
+            labels.insert(field.clone(), value);
+        }
+        Ok(labels)
+    }
+
+    fn client(&self) -> Result<Client> {
+        let mut client = self.client.borrow_mut();
+        if client.is_none() {
+            *client = Some(http_client(TIMEOUT_SECONDS)?);
+        }
+        Ok(client.clone().unwrap())
+    }
+}
+
+impl Drop for LokiOutput {
+    fn drop(&mut self) {
+        self.flush().expect("failed to push logs to loki");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::log_lines;
+    use crate::output::buffered_trait::BufferedLogProcessor;
+    use crate::output::loki::LokiOutput;
+
+    #[test]
+    fn test_push_request() {
+        let output: LokiOutput = serde_json::from_value(json!({
+            "url": "http://localhost:3100",
+            "labels": ["elb_name", "status_class"],
+            "static_labels": {"job": "alb"},
+        }))
+        .unwrap();
+        for line in log_lines().into_iter().rev() {
+            output.push_to_buffer(line);
+        }
+
+        let push = output.push_request().unwrap();
+        let mut entries = 0;
+        for stream in push["streams"].as_array().unwrap() {
+            assert_eq!("alb", stream["stream"]["job"]);
+            assert!(stream["stream"]["elb_name"].is_string());
+            let mut last = 0;
+            for value in stream["values"].as_array().unwrap() {
+                let timestamp: u128 = value[0].as_str().unwrap().parse().unwrap();
+                assert!(timestamp >= last, "stream pushed out of order");
+                last = timestamp;
+                let line: serde_json::Value =
+                    serde_json::from_str(value[1].as_str().unwrap()).unwrap();
+                assert_eq!(stream["stream"]["elb_name"], line["elb_name"]);
+                entries += 1;
+            }
+        }
+        assert_eq!(10, entries);
+        // Nothing is listening, the lines must not be pushed on drop
+        output.buffer_clear();
+    }
+}
@@ -1,24 +1,88 @@
-use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fmt;
+use std::time::Duration;
+
+use rusoto_core::Region;
+use schemars::JsonSchema;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
 
 pub use crate::output::cloudwatch_logs::CloudwatchLogOutput;
 pub use crate::output::cloudwatch_metric::CloudwatchMetricOutput;
+pub use crate::output::elasticsearch::ElasticsearchOutput;
+pub use crate::output::firehose::FirehoseOutput;
+pub use crate::output::format::LineFormat;
+pub use crate::output::http::HttpOutput;
+pub use crate::output::kafka::KafkaOutput;
+pub use crate::output::kinesis::KinesisOutput;
+pub use crate::output::loki::LokiOutput;
+pub use crate::output::otlp::OtlpOutput;
+pub use crate::output::parquet::ParquetOutput;
+pub use crate::output::prometheus::PrometheusOutput;
+pub use crate::output::s3::S3Output;
+pub use crate::output::sns::SnsOutput;
+pub use crate::output::sqs::SqsOutput;
+pub use crate::output::statsd::StatsdOutput;
 pub use crate::output::stdout::StdoutOutput;
 pub use crate::output::void::VoidOutput;
-use crate::types::LogProcessor;
+use crate::types::{is_field_name, LogProcessor, LATENCY_FIELD_NAMES};
+
+/// How many times outputs retry a batch that was partially rejected
+pub(crate) const MAXIMUM_RETRIES: u32 = 5;
 
 pub mod buffered_trait;
 pub mod cloudwatch_logs;
 pub mod cloudwatch_metric;
+pub mod elasticsearch;
+pub mod firehose;
+pub mod format;
+pub mod http;
+pub mod kafka;
+pub mod kinesis;
+pub mod loki;
+pub mod otlp;
+pub mod parquet;
+pub mod prometheus;
+pub mod s3;
+pub mod sns;
+pub mod sqs;
+pub mod statsd;
 pub mod stdout;
 pub mod void;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type")]
 pub enum OutputType {
     #[serde(rename = "cloudwatch_metric")]
     CloudwatchMetric(CloudwatchMetricOutput),
     #[serde(rename = "cloudwatch_log")]
     CloudwatchLog(CloudwatchLogOutput),
+    #[serde(rename = "elasticsearch")]
+    Elasticsearch(ElasticsearchOutput),
+    #[serde(rename = "firehose")]
+    Firehose(FirehoseOutput),
+    #[serde(rename = "http")]
+    Http(HttpOutput),
+    #[serde(rename = "kafka")]
+    Kafka(KafkaOutput),
+    #[serde(rename = "kinesis")]
+    Kinesis(KinesisOutput),
+    #[serde(rename = "loki")]
+    Loki(LokiOutput),
+    #[serde(rename = "otlp")]
+    Otlp(OtlpOutput),
+    #[serde(rename = "parquet")]
+    Parquet(ParquetOutput),
+    #[serde(rename = "prometheus")]
+    Prometheus(PrometheusOutput),
+    #[serde(rename = "s3")]
+    S3(S3Output),
+    #[serde(rename = "sns")]
+    Sns(SnsOutput),
+    #[serde(rename = "sqs")]
+    Sqs(SqsOutput),
+    #[serde(rename = "statsd")]
+    Statsd(StatsdOutput),
     #[serde(rename = "stdout")]
     Stdout(StdoutOutput),
     #[serde(rename = "void")]
@@ -30,8 +94,199 @@ impl OutputType {
         match self {
             OutputType::CloudwatchMetric(o) => o,
             OutputType::CloudwatchLog(o) => o,
+            OutputType::Elasticsearch(o) => o,
+            OutputType::Firehose(o) => o,
+            OutputType::Http(o) => o,
+            OutputType::Kafka(o) => o,
+            OutputType::Kinesis(o) => o,
+            OutputType::Loki(o) => o,
+            OutputType::Otlp(o) => o,
+            OutputType::Parquet(o) => o,
+            OutputType::Prometheus(o) => o,
+            OutputType::S3(o) => o,
+            OutputType::Sns(o) => o,
+            OutputType::Sqs(o) => o,
+            OutputType::Statsd(o) => o,
             OutputType::Stdout(o) => o,
             OutputType::Void(o) => o,
         }
     }
 }
+
+/// A client created on first use and kept for as long as its output, which is the whole lambda
+/// container, so connections are reused between invocations
+pub(crate) struct CachedClient<T>(RefCell<Option<T>>);
+
+impl<T: Clone> CachedClient<T> {
+    pub fn get_or_create<F: FnOnce() -> T>(&self, create: F) -> T {
+        self.0.borrow_mut().get_or_insert_with(create).clone()
+    }
+
+    /// Like `get_or_create` for clients whose creation can fail, like HTTP clients
+    pub fn get_or_try_create<F: FnOnce() -> anyhow::Result<T>>(
+        &self,
+        create: F,
+    ) -> anyhow::Result<T> {
+        let mut client = self.0.borrow_mut();
+        if client.is_none() {
+            *client = Some(create()?);
+        }
+        Ok(client.clone().unwrap())
+    }
+}
+
+impl<T> Default for CachedClient<T> {
+    fn default() -> Self {
+        CachedClient(RefCell::new(None))
+    }
+}
+
+impl<T> fmt::Debug for CachedClient<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CachedClient")
+    }
+}
+
+/// Points a region to a custom endpoint, used to talk to local stand-ins like MinIO
+pub(crate) fn region_with_endpoint(region: &Region, endpoint: &Option<String>) -> Region {
+    match endpoint {
+        Some(endpoint) => Region::Custom {
+            name: region.name().to_owned(),
+            endpoint: endpoint.clone(),
+        },
+        None => region.clone(),
+    }
+}
+
+/// Rejects fields that are not log or derived fields when the configuration is loaded
+pub(crate) fn deserialize_field_name<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let field = Option::<String>::deserialize(deserializer)?;
+    match &field {
+        Some(name) if !is_field_name(name) => {
+            Err(D::Error::custom(format!("unknown field {:?}", name)))
+        }
+        _ => Ok(field),
+    }
+}
+
+/// Like `deserialize_field_name`, for a list of fields
+pub(crate) fn deserialize_field_names<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let fields = Vec::<String>::deserialize(deserializer)?;
+    match fields.iter().find(|name| !is_field_name(name)) {
+        Some(name) => Err(D::Error::custom(format!("unknown field {:?}", name))),
+        None => Ok(fields),
+    }
+}
+
+/// Like `deserialize_latency_fields`, for a single field
+pub(crate) fn deserialize_latency_field<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let field = String::deserialize(deserializer)?;
+    check_latency_fields(&[field.clone()])?;
+    Ok(field)
+}
+
+/// Only accepts the fields of `LATENCY_FIELD_NAMES`, other fields are not durations
+pub(crate) fn deserialize_latency_fields<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let fields = Vec::<String>::deserialize(deserializer)?;
+    check_latency_fields(&fields)?;
+    Ok(fields)
+}
+
+fn check_latency_fields<E: serde::de::Error>(fields: &[String]) -> Result<(), E> {
+    match fields
+        .iter()
+        .find(|name| !LATENCY_FIELD_NAMES.contains(&name.as_str()))
+    {
+        Some(name) => Err(E::custom(format!(
+            "{:?} is not a latency field, expected one of {}",
+            name,
+            LATENCY_FIELD_NAMES.join(", ")
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Exponential backoff used between retries, starting at 100ms
+pub(crate) fn backoff(attempt: u32) -> Duration {
+    Duration::from_millis(100 * 2u64.pow(attempt.saturating_sub(1)))
+}
+
+/// Splits items into batches of at most `maximum_count` items whose total size stays under
+/// `maximum_size`, an item bigger than that gets a batch of its own
+pub(crate) fn split_batches<T, F>(
+    items: Vec<T>,
+    maximum_count: usize,
+    maximum_size: usize,
+    size: F,
+) -> Vec<Vec<T>>
+where
+    F: Fn(&T) -> usize,
+{
+    let mut batches = vec![];
+    let mut batch = vec![];
+    let mut batch_size = 0;
+
+    for item in items {
+        let item_size = size(&item);
+        if !batch.is_empty()
+            && (batch.len() >= maximum_count || batch_size + item_size > maximum_size)
+        {
+            batches.push(std::mem::take(&mut batch));
+            batch_size = 0;
+        }
+        batch_size += item_size;
+        batch.push(item);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::output::{backoff, split_batches};
+
+    #[test]
+    fn test_split_batches() {
+        let sizes = |batches: Vec<Vec<usize>>| -> Vec<usize> {
+            batches.iter().map(|batch| batch.len()).collect()
+        };
+        assert_eq!(
+            vec![500, 500, 1],
+            sizes(split_batches(vec![1; 1001], 500, usize::MAX, |item| *item))
+        );
+        assert_eq!(
+            vec![2, 2, 1],
+            sizes(split_batches(vec![2; 5], 500, 4, |item| *item))
+        );
+        // Batches can be exactly at the limit, bigger items are sent alone
+        assert_eq!(
+            vec![vec![3, 1], vec![10], vec![4]],
+            split_batches(vec![3, 1, 10, 4], 500, 4, |item| *item)
+        );
+        assert!(split_batches(Vec::<usize>::new(), 500, 4, |item| *item).is_empty());
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(Duration::from_millis(100), backoff(1));
+        assert_eq!(Duration::from_millis(200), backoff(2));
+        assert_eq!(Duration::from_millis(1600), backoff(5));
+    }
+}
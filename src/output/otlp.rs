@@ -0,0 +1,672 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::error;
+use prost::{Message, Oneof};
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::http::{http_client, send_with_retries};
+use crate::output::prometheus::deserialize_buckets;
+use crate::output::{deserialize_field_names, deserialize_latency_field, CachedClient};
+use crate::types::{LogProcessor, RequestLogLine, FIELD_NAMES};
+
+const BUFFER_SIZE: usize = 500;
+const TIMEOUT_SECONDS: u64 = 10;
+const SEVERITY_INFO: i32 = 9;
+const SEVERITY_WARN: i32 = 13;
+const SEVERITY_ERROR: i32 = 17;
+const AGGREGATION_TEMPORALITY_DELTA: i32 = 1;
+
+/// Exports matched lines as OpenTelemetry log records to a collector using OTLP/HTTP with
+/// protobuf, optionally deriving request metrics from the same lines
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpOutput {
+    /// Base url of the collector, e.g. `http://localhost:4318`
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub metrics: Option<OtlpMetrics>,
+    #[serde(skip)]
+    buffer: RefCell<Vec<RequestLogLine>>,
+    #[serde(skip)]
+    series: RefCell<BTreeMap<Vec<(String, String)>, Series>>,
+    #[serde(skip)]
+    client: CachedClient<Client>,
+}
+
+/// Metrics aggregated per log file and exported with delta temporality when it finishes
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpMetrics {
+    /// Prefix of the metrics, generating `<metric_name>.requests` and `<metric_name>.latency`
+    pub metric_name: String,
+    #[serde(
+        default = "default_attributes",
+        deserialize_with = "deserialize_field_names"
+    )]
+    pub attributes: Vec<String>,
+    #[serde(default = "default_buckets", deserialize_with = "deserialize_buckets")]
+    pub buckets: Vec<f64>,
+    #[serde(
+        default = "default_latency_field",
+        deserialize_with = "deserialize_latency_field"
+    )]
+    pub latency_field: String,
+}
+
+fn default_service_name() -> String {
+    env!("CARGO_PKG_NAME").to_string()
+}
+
+fn default_attributes() -> Vec<String> {
+    vec![
+        "elb_name".to_string(),
+        "target_group".to_string(),
+        "status_class".to_string(),
+    ]
+}
+
+fn default_buckets() -> Vec<f64> {
+    vec![
+        0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+    ]
+}
+
+fn default_latency_field() -> String {
+    "target_processing_time".to_string()
+}
+
+#[derive(Debug)]
+struct Series {
+    requests: u64,
+    /// Count of observations per bucket, with an extra one for values above the last bound
+    bucket_counts: Vec<u64>,
+    latency_sum: f64,
+    latency_count: u64,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+}
+
+impl LogProcessor for OtlpOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        if let Some(metrics) = &self.metrics {
+            self.aggregate(metrics, log_line)?;
+        }
+        self.add_to_queue(&log_line)?;
+        Ok(())
+    }
+
+    /// The metrics are exported even when the log records failed, they belong to this file only
+    fn finish(&self) -> Result<()> {
+        let flushed = self.flush();
+        let exported = match &self.metrics {
+            Some(metrics) => self.export_metrics(metrics),
+            None => Ok(()),
+        };
+        match (flushed, exported) {
+            (Err(logs), Err(metrics)) => {
+                Err(logs.context(format!("exporting the metrics failed too: {:#}", metrics)))
+            }
+            (flushed, exported) => flushed.and(exported),
+        }
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
+}
+
+impl BufferedLogProcessor for OtlpOutput {
+    fn maximum_buffer_size(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    fn buffer_clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+
+    fn push_to_buffer(&self, log_line: RequestLogLine) {
+        self.buffer.borrow_mut().push(log_line);
+    }
+
+    fn process_log_lines(&self) -> Result<()> {
+        let now = timestamp_nanos(Utc::now());
+        let log_records = self
+            .buffer
+            .borrow()
+            .iter()
+            .map(|line| LogRecord {
+                time_unix_nano: timestamp_nanos(line.timestamp),
+                observed_time_unix_nano: now,
+                severity_number: severity(line.elb_status_code),
+                severity_text: line.status_class(),
+                body: Some(string_value(line.field_value("request").unwrap())),
+                attributes: FIELD_NAMES
+                    .iter()
+                    .map(|field| key_value(field, line.field_value(field).unwrap()))
+                    .collect(),
+                trace_id: trace_id(&line.trace_id).unwrap_or_default(),
+            })
+            .collect();
+
+        let request = ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(self.resource()),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(scope()),
+                    log_records,
+                }],
+            }],
+        };
+        self.export("v1/logs", request)
+    }
+}
+
+impl OtlpOutput {
+    fn aggregate(&self, metrics: &OtlpMetrics, line: &RequestLogLine) -> Result<()> {
+        let attributes = metrics
+            .attributes
+            .iter()
+            .map(|field| {
+                let value = line
+                    .field_value(field)
+                    .with_context(|| format!("unknown attribute field {}", field))?;
+                Ok((field.clone(), value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let latency = line
+            .field_value(&metrics.latency_field)
+            .with_context(|| format!("unknown latency field {}", metrics.latency_field))?
+            .parse::<f64>()
+            .ok()
+            .filter(|latency| *latency >= 0.0);
+
+        let mut series = self.series.borrow_mut();
+        let series = series.entry(attributes).or_insert_with(|| Series {
+            requests: 0,
+            bucket_counts: vec![0; metrics.buckets.len() + 1],
+            latency_sum: 0.0,
+            latency_count: 0,
+            start: line.timestamp,
+            end: line.timestamp,
+        });
+        series.requests += 1;
+        series.start = series.start.min(line.timestamp);
+        series.end = series.end.max(line.timestamp);
+        if let Some(latency) = latency {
+            let bucket = metrics
+                .buckets
+                .iter()
+                .position(|bound| latency <= *bound)
+                .unwrap_or(metrics.buckets.len());
+            series.bucket_counts[bucket] += 1;
+            series.latency_sum += latency;
+            series.latency_count += 1;
+        }
+        Ok(())
+    }
+
+    fn export_metrics(&self, metrics: &OtlpMetrics) -> Result<()> {
+        let series = mem::take(&mut *self.series.borrow_mut());
+        if series.is_empty() {
+            return Ok(());
+        }
+
+        let mut requests = vec![];
+        let mut latencies = vec![];
+        for (attributes, series) in series {
+            let attributes: Vec<KeyValue> = attributes
+                .into_iter()
+                .map(|(key, value)| key_value(&key, value))
+                .collect();
+            let start_time_unix_nano = timestamp_nanos(series.start);
+            let time_unix_nano = timestamp_nanos(series.end);
+            requests.push(NumberDataPoint {
+                attributes: attributes.clone(),
+                start_time_unix_nano,
+                time_unix_nano,
+                value: Some(NumberValue::AsInt(series.requests as i64)),
+            });
+            latencies.push(HistogramDataPoint {
+                attributes,
+                start_time_unix_nano,
+                time_unix_nano,
+                count: series.latency_count,
+                sum: Some(series.latency_sum),
+                bucket_counts: series.bucket_counts,
+                explicit_bounds: metrics.buckets.clone(),
+            });
+        }
+
+        let request = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(self.resource()),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(scope()),
+                    metrics: vec![
+                        Metric {
+                            name: format!("{}.requests", metrics.metric_name),
+                            description: "Requests matched by the pipeline".to_string(),
+                            unit: "{request}".to_string(),
+                            data: Some(MetricData::Sum(Sum {
+                                data_points: requests,
+                                aggregation_temporality: AGGREGATION_TEMPORALITY_DELTA,
+                                is_monotonic: true,
+                            })),
+                        },
+                        Metric {
+                            name: format!("{}.latency", metrics.metric_name),
+                            description: format!("Distribution of {}", metrics.latency_field),
+                            unit: "s".to_string(),
+                            data: Some(MetricData::Histogram(Histogram {
+                                data_points: latencies,
+                                aggregation_temporality: AGGREGATION_TEMPORALITY_DELTA,
+                            })),
+                        },
+                    ],
+                }],
+            }],
+        };
+        self.export("v1/metrics", request)
+    }
+
+    fn export<M: Message>(&self, path: &str, message: M) -> Result<()> {
+        let mut body = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut body)?;
+
+        let client = self
+            .client
+            .get_or_try_create(|| http_client(TIMEOUT_SECONDS))?;
+        let url = format!("{}/{}", self.endpoint.trim_end_matches('/'), path);
+        send_with_retries(&url, || {
+            let mut request = client
+                .post(&url)
+                .header(CONTENT_TYPE, "application/x-protobuf")
+                .body(body.clone());
+            for (name, value) in &self.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            request
+        })?;
+        Ok(())
+    }
+
+    fn resource(&self) -> Resource {
+        Resource {
+            attributes: vec![key_value("service.name", self.service_name.clone())],
+        }
+    }
+}
+
+impl Drop for OtlpOutput {
+    fn drop(&mut self) {
+        // An unreachable collector must not panic the container
+        if let Err(error) = self.finish() {
+            error!("Failed to export to the otlp collector {:?}", error);
+        }
+    }
+}
+
+fn severity(status_code: u16) -> i32 {
+    match status_code {
+        500..=599 => SEVERITY_ERROR,
+        400..=499 => SEVERITY_WARN,
+        _ => SEVERITY_INFO,
+    }
+}
+
+fn timestamp_nanos(timestamp: DateTime<Utc>) -> u64 {
+    timestamp.timestamp_nanos() as u64
+}
+
+fn scope() -> InstrumentationScope {
+    InstrumentationScope {
+        name: env!("CARGO_PKG_NAME").to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+fn string_value(value: String) -> AnyValue {
+    AnyValue {
+        value: Some(AnyValueKind::StringValue(value)),
+    }
+}
+
+fn key_value(key: &str, value: String) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(string_value(value)),
+    }
+}
+
+/// Converts the `Root=1-5e437e01-4e71d9e73f859ab83a1c22ec` part of an X-Amzn-Trace-Id into the 16
+/// bytes of a W3C trace id
+fn trace_id(amzn_trace_id: &str) -> Option<Vec<u8>> {
+    let root = amzn_trace_id
+        .split(';')
+        .find_map(|part| part.trim().strip_prefix("Root="))?;
+    let mut parts = root.split('-');
+    if parts.next()? != "1" {
+        return None;
+    }
+    let hex = parts.collect::<String>();
+    if hex.len() != 32 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+// Subset of the OTLP protobuf messages from `opentelemetry-proto`
+
+#[derive(Clone, PartialEq, Message)]
+struct ExportLogsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    resource_logs: Vec<ResourceLogs>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ResourceLogs {
+    #[prost(message, optional, tag = "1")]
+    resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    scope_logs: Vec<ScopeLogs>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ScopeLogs {
+    #[prost(message, optional, tag = "1")]
+    scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    log_records: Vec<LogRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LogRecord {
+    #[prost(fixed64, tag = "1")]
+    time_unix_nano: u64,
+    #[prost(fixed64, tag = "11")]
+    observed_time_unix_nano: u64,
+    #[prost(int32, tag = "2")]
+    severity_number: i32,
+    #[prost(string, tag = "3")]
+    severity_text: String,
+    #[prost(message, optional, tag = "5")]
+    body: Option<AnyValue>,
+    #[prost(message, repeated, tag = "6")]
+    attributes: Vec<KeyValue>,
+    #[prost(bytes, tag = "9")]
+    trace_id: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ScopeMetrics {
+    #[prost(message, optional, tag = "1")]
+    scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Metric {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    description: String,
+    #[prost(string, tag = "3")]
+    unit: String,
+    #[prost(oneof = "MetricData", tags = "7, 9")]
+    data: Option<MetricData>,
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+enum MetricData {
+    #[prost(message, tag = "7")]
+    Sum(Sum),
+    #[prost(message, tag = "9")]
+    Histogram(Histogram),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sum {
+    #[prost(message, repeated, tag = "1")]
+    data_points: Vec<NumberDataPoint>,
+    #[prost(int32, tag = "2")]
+    aggregation_temporality: i32,
+    #[prost(bool, tag = "3")]
+    is_monotonic: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Histogram {
+    #[prost(message, repeated, tag = "1")]
+    data_points: Vec<HistogramDataPoint>,
+    #[prost(int32, tag = "2")]
+    aggregation_temporality: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "2")]
+    start_time_unix_nano: u64,
+    #[prost(fixed64, tag = "3")]
+    time_unix_nano: u64,
+    #[prost(oneof = "NumberValue", tags = "6")]
+    value: Option<NumberValue>,
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+enum NumberValue {
+    #[prost(sfixed64, tag = "6")]
+    AsInt(i64),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct HistogramDataPoint {
+    #[prost(message, repeated, tag = "9")]
+    attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "2")]
+    start_time_unix_nano: u64,
+    #[prost(fixed64, tag = "3")]
+    time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    count: u64,
+    #[prost(double, optional, tag = "5")]
+    sum: Option<f64>,
+    #[prost(fixed64, repeated, tag = "6")]
+    bucket_counts: Vec<u64>,
+    #[prost(double, repeated, tag = "7")]
+    explicit_bounds: Vec<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    version: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct KeyValue {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(message, optional, tag = "2")]
+    value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct AnyValue {
+    #[prost(oneof = "AnyValueKind", tags = "1")]
+    value: Option<AnyValueKind>,
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+enum AnyValueKind {
+    #[prost(string, tag = "1")]
+    StringValue(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::log_lines;
+    use crate::output::http::mock_server;
+    use crate::output::otlp::{
+        key_value, trace_id, ExportLogsServiceRequest, ExportMetricsServiceRequest, MetricData,
+        NumberValue, OtlpOutput,
+    };
+    use crate::types::{LogProcessor, FIELD_NAMES};
+
+    fn output(endpoint: &str) -> OtlpOutput {
+        let output: OtlpOutput = serde_json::from_value(json!({
+            "endpoint": endpoint,
+            "service_name": "alb",
+            "metrics": {
+                "metric_name": "alb",
+                "attributes": ["status_class"],
+                "buckets": [0.1, 1.0],
+            },
+        }))
+        .unwrap();
+        // Latencies of 0.250, 0.001 and 0.009 seconds
+        for line in log_lines().iter().take(3) {
+            output.process_line(line).unwrap();
+        }
+        output
+    }
+
+    #[test]
+    fn test_export() {
+        let (url, bodies) = mock_server(vec![(200, ""), (200, "")]);
+        output(&url).finish().unwrap();
+
+        let logs = ExportLogsServiceRequest::decode(&bodies.recv().unwrap()[..]).unwrap();
+        let resource_logs = &logs.resource_logs[0];
+        assert_eq!(
+            vec![key_value("service.name", "alb".to_string())],
+            resource_logs.resource.as_ref().unwrap().attributes
+        );
+        let records = &resource_logs.scope_logs[0].log_records;
+        assert_eq!(3, records.len());
+        assert_eq!(FIELD_NAMES.len(), records[0].attributes.len());
+        assert_eq!("2xx", records[0].severity_text);
+        assert_eq!(16, records[0].trace_id.len());
+
+        let metrics = ExportMetricsServiceRequest::decode(&bodies.recv().unwrap()[..]).unwrap();
+        let metrics = &metrics.resource_metrics[0].scope_metrics[0].metrics;
+        assert_eq!("alb.requests", metrics[0].name);
+        match &metrics[0].data {
+            Some(MetricData::Sum(sum)) => {
+                assert_eq!(1, sum.data_points.len());
+                assert_eq!(
+                    vec![key_value("status_class", "2xx".to_string())],
+                    sum.data_points[0].attributes
+                );
+                assert_eq!(Some(NumberValue::AsInt(3)), sum.data_points[0].value);
+            }
+            data => panic!("expected a sum, got {:?}", data),
+        }
+        assert_eq!("alb.latency", metrics[1].name);
+        match &metrics[1].data {
+            Some(MetricData::Histogram(histogram)) => {
+                let point = &histogram.data_points[0];
+                assert_eq!(vec![0.1, 1.0], point.explicit_bounds);
+                assert_eq!(vec![2, 1, 0], point.bucket_counts);
+                assert_eq!(3, point.count);
+            }
+            data => panic!("expected a histogram, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn test_metrics_exported_when_logs_fail() {
+        let (url, bodies) = mock_server(vec![(400, ""), (200, "")]);
+        let output = output(&url);
+        let error = output.finish().unwrap_err();
+        assert!(format!("{:#}", error).contains("400"));
+
+        bodies.recv().unwrap();
+        let metrics = ExportMetricsServiceRequest::decode(&bodies.recv().unwrap()[..]).unwrap();
+        assert_eq!(
+            2,
+            metrics.resource_metrics[0].scope_metrics[0].metrics.len()
+        );
+        assert!(output.series.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        for metrics in &[
+            json!({"metric_name": "alb", "attributes": ["status"]}),
+            json!({"metric_name": "alb", "latency_field": "elb_status_code"}),
+        ] {
+            let output = serde_json::from_value::<OtlpOutput>(json!({
+                "endpoint": "http://localhost:4318",
+                "metrics": metrics,
+            }));
+            assert!(output.is_err());
+        }
+    }
+
+    #[test]
+    fn test_trace_id_from_amzn_trace_id() {
+        let expected = vec![
+            0x5e, 0x43, 0x7e, 0x01, 0x4e, 0x71, 0xd9, 0xe7, 0x3f, 0x85, 0x9a, 0xb8, 0x3a, 0x1c,
+            0x22, 0xec,
+        ];
+        assert_eq!(
+            Some(expected.clone()),
+            trace_id("Root=1-5e437e01-4e71d9e73f859ab83a1c22ec")
+        );
+        assert_eq!(
+            Some(expected),
+            trace_id(
+                "Self=1-5e437e01-aaaaaaaaaaaaaaaaaaaaaaaa;Root=1-5e437e01-4e71d9e73f859ab83a1c22ec"
+            )
+        );
+        assert_eq!(None, trace_id("-"));
+        assert_eq!(None, trace_id("Root=1-5e437e01-zz"));
+    }
+}
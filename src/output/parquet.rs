@@ -0,0 +1,424 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+
+use anyhow::Result;
+use arrow::array::{
+    ArrayRef, Float64Builder, StringBuilder, TimestampMicrosecondBuilder, UInt16Builder,
+    UInt64Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use log::error;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::InMemoryWriteableCursor;
+use rusoto_core::Region;
+use rusoto_s3::S3Client;
+use schemars::JsonSchema;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+
+use crate::output::{region_with_endpoint, CachedClient};
+use crate::s3::{get_s3_client, put_object};
+use crate::template;
+use crate::types::{LogProcessor, MaybeNumber, Request, RequestLogLine};
+
+const DEFAULT_ROW_GROUP_SIZE: usize = 100_000;
+
+lazy_static::lazy_static! {
+    static ref SCHEMA: SchemaRef = {
+        let timestamp = DataType::Timestamp(TimeUnit::Microsecond, None);
+        Arc::new(Schema::new(vec![
+            Field::new("request_type", DataType::Utf8, false),
+            Field::new("timestamp", timestamp.clone(), false),
+            Field::new("elb_name", DataType::Utf8, false),
+            Field::new("client", DataType::Utf8, false),
+            Field::new("target", DataType::Utf8, false),
+            Field::new("request_processing_time", DataType::Float64, true),
+            Field::new("target_processing_time", DataType::Float64, true),
+            Field::new("response_processing_time", DataType::Float64, true),
+            Field::new("elb_status_code", DataType::UInt16, false),
+            Field::new("target_status_code", DataType::UInt16, true),
+            Field::new("received_bytes", DataType::UInt64, false),
+            Field::new("sent_bytes", DataType::UInt64, false),
+            // Null when the request can't be split
+            Field::new("method", DataType::Utf8, true),
+            Field::new("path", DataType::Utf8, true),
+            Field::new("http_version", DataType::Utf8, true),
+            Field::new("user_agent", DataType::Utf8, false),
+            Field::new("ssl_cipher", DataType::Utf8, false),
+            Field::new("ssl_protocol", DataType::Utf8, false),
+            Field::new("target_group_arn", DataType::Utf8, false),
+            Field::new("trace_id", DataType::Utf8, false),
+            Field::new("domain_name", DataType::Utf8, false),
+            Field::new("chosen_cert_arn", DataType::Utf8, false),
+            Field::new("matched_rule_priority", DataType::Utf8, false),
+            Field::new("request_creation_time", timestamp, false),
+            Field::new("actions_executed", DataType::Utf8, false),
+            Field::new("error_reason", DataType::Utf8, false),
+        ]))
+    };
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ParquetCompression {
+    Snappy,
+    Zstd,
+    None,
+}
+
+impl Default for ParquetCompression {
+    fn default() -> Self {
+        ParquetCompression::Snappy
+    }
+}
+
+impl From<ParquetCompression> for Compression {
+    fn from(compression: ParquetCompression) -> Self {
+        match compression {
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd => Compression::ZSTD,
+            ParquetCompression::None => Compression::UNCOMPRESSED,
+        }
+    }
+}
+
+/// Converts matched lines to parquet files with a fixed schema and uploads them to S3.
+///
+/// Like `S3Output`, one object is written per processed log file and rendered `key_prefix`.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ParquetOutput {
+    pub bucket: String,
+    #[serde(default)]
+    pub key_prefix: String,
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub compression: ParquetCompression,
+    #[serde(
+        default = "default_row_group_size",
+        deserialize_with = "deserialize_row_group_size"
+    )]
+    pub row_group_size: usize,
+    #[serde(skip)]
+    files: RefCell<HashMap<String, ParquetFile>>,
+    #[serde(skip)]
+    aws_region: Region,
+    #[serde(skip)]
+    client: CachedClient<S3Client>,
+}
+
+fn default_row_group_size() -> usize {
+    DEFAULT_ROW_GROUP_SIZE
+}
+
+/// Row groups of 0 rows can't be written, the parquet writer asserts they have at least one
+fn deserialize_row_group_size<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match usize::deserialize(deserializer)? {
+        0 => Err(D::Error::custom("row_group_size must be at least 1")),
+        row_group_size => Ok(row_group_size),
+    }
+}
+
+struct ParquetFile {
+    key: String,
+    cursor: InMemoryWriteableCursor,
+    writer: ArrowWriter<InMemoryWriteableCursor>,
+    rows: Vec<RequestLogLine>,
+    /// Rows added to the file, sent once it is uploaded
+    lines: usize,
+}
+
+impl fmt::Debug for ParquetFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParquetFile")
+            .field("key", &self.key)
+            .field("rows", &self.rows.len())
+            .finish()
+    }
+}
+
+impl LogProcessor for ParquetOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        let prefix = template::render_key(&self.key_prefix, log_line)?;
+        let mut files = self.files.borrow_mut();
+        if !files.contains_key(&prefix) {
+            let file = self.new_file(&prefix)?;
+            files.insert(prefix.clone(), file);
+        }
+        let file = files.get_mut(&prefix).unwrap();
+
+        file.rows.push(log_line.clone());
+        file.lines += 1;
+        if file.rows.len() >= self.row_group_size {
+            if let Err(error) = file.write_row_group() {
+                // The file can't be written anymore, its lines are dropped
+                files.remove(&prefix);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        let files = mem::take(&mut *self.files.borrow_mut());
+        let mut result = Ok(());
+        // Every file is uploaded even when one of them fails
+        for (_, file) in files {
+            if let Err(error) = self.upload_file(file) {
+                error!("Failed to upload parquet file {:?}", error);
+                result = result.and(Err(error));
+            }
+        }
+        result
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.files.borrow().values().map(|file| file.lines).sum()
+    }
+}
+
+impl ParquetOutput {
+    fn new_file(&self, prefix: &str) -> Result<ParquetFile> {
+        let cursor = InMemoryWriteableCursor::default();
+        let properties = WriterProperties::builder()
+            .set_compression(self.compression.into())
+            .set_max_row_group_size(self.row_group_size)
+            .build();
+        let writer = ArrowWriter::try_new(cursor.clone(), SCHEMA.clone(), Some(properties))?;
+
+        Ok(ParquetFile {
+            key: format!("{}{}.parquet", prefix, Uuid::new_v4()),
+            cursor,
+            writer,
+            rows: Vec::with_capacity(self.row_group_size),
+            lines: 0,
+        })
+    }
+
+    fn upload_file(&self, mut file: ParquetFile) -> Result<()> {
+        file.write_row_group()?;
+        file.writer.close()?;
+        put_object(
+            &self.s3_client(),
+            &self.bucket,
+            &file.key,
+            file.cursor.data(),
+            "application/octet-stream",
+            None,
+        )
+    }
+
+    fn s3_client(&self) -> S3Client {
+        self.client.get_or_create(|| {
+            get_s3_client(&region_with_endpoint(&self.aws_region, &self.endpoint))
+        })
+    }
+}
+
+impl ParquetFile {
+    fn write_row_group(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let batch = record_batch(&self.rows)?;
+        self.writer.write(&batch)?;
+        self.rows.clear();
+        Ok(())
+    }
+}
+
+impl Drop for ParquetOutput {
+    fn drop(&mut self) {
+        self.finish().expect("failed to upload parquet files");
+    }
+}
+
+fn record_batch(lines: &[RequestLogLine]) -> Result<RecordBatch> {
+    let requests: Vec<Option<Request>> = lines.iter().map(RequestLogLine::try_request).collect();
+    let columns: Vec<ArrayRef> = vec![
+        string_column(lines, |line| &line.request_type)?,
+        timestamp_column(lines, |line| line.timestamp.timestamp_nanos() / 1000)?,
+        string_column(lines, |line| &line.elb_name)?,
+        string_column(lines, |line| &line.client)?,
+        string_column(lines, |line| &line.target)?,
+        float_column(lines, |line| &line.request_processing_time)?,
+        float_column(lines, |line| &line.target_processing_time)?,
+        float_column(lines, |line| &line.response_processing_time)?,
+        u16_column(lines, |line| Some(line.elb_status_code))?,
+        u16_column(lines, |line| line.target_status_code.number())?,
+        u64_column(lines, |line| line.received_bytes)?,
+        u64_column(lines, |line| line.sent_bytes)?,
+        request_column(&requests, |request| &request.method)?,
+        request_column(&requests, |request| &request.path)?,
+        request_column(&requests, |request| &request.http_version)?,
+        string_column(lines, |line| &line.user_agent)?,
+        string_column(lines, |line| &line.ssl_cipher)?,
+        string_column(lines, |line| &line.ssl_protocol)?,
+        string_column(lines, |line| &line.target_group_arn)?,
+        string_column(lines, |line| &line.trace_id)?,
+        string_column(lines, |line| &line.domain_name)?,
+        string_column(lines, |line| &line.chosen_cert_arn)?,
+        string_column(lines, |line| &line.matched_rule_priority)?,
+        timestamp_column(lines, |line| {
+            line.request_creation_time.timestamp_nanos() / 1000
+        })?,
+        string_column(lines, |line| &line.actions_executed)?,
+        string_column(lines, |line| &line.error_reason)?,
+    ];
+
+    Ok(RecordBatch::try_new(SCHEMA.clone(), columns)?)
+}
+
+fn string_column<F>(lines: &[RequestLogLine], value: F) -> Result<ArrayRef>
+where
+    F: Fn(&RequestLogLine) -> &String,
+{
+    let mut builder = StringBuilder::new(lines.len());
+    for line in lines {
+        builder.append_value(value(line))?;
+    }
+    Ok(Arc::new(builder.finish()))
+}
+
+fn request_column<F>(requests: &[Option<Request>], value: F) -> Result<ArrayRef>
+where
+    F: Fn(&Request) -> &String,
+{
+    let mut builder = StringBuilder::new(requests.len());
+    for request in requests {
+        match request {
+            Some(request) => builder.append_value(value(request))?,
+            None => builder.append_null()?,
+        }
+    }
+    Ok(Arc::new(builder.finish()))
+}
+
+fn float_column<F>(lines: &[RequestLogLine], value: F) -> Result<ArrayRef>
+where
+    F: Fn(&RequestLogLine) -> &MaybeNumber<f64>,
+{
+    let mut builder = Float64Builder::new(lines.len());
+    for line in lines {
+        builder.append_option(value(line).number())?;
+    }
+    Ok(Arc::new(builder.finish()))
+}
+
+fn u16_column<F>(lines: &[RequestLogLine], value: F) -> Result<ArrayRef>
+where
+    F: Fn(&RequestLogLine) -> Option<u16>,
+{
+    let mut builder = UInt16Builder::new(lines.len());
+    for line in lines {
+        builder.append_option(value(line))?;
+    }
+    Ok(Arc::new(builder.finish()))
+}
+
+fn u64_column<F>(lines: &[RequestLogLine], value: F) -> Result<ArrayRef>
+where
+    F: Fn(&RequestLogLine) -> u64,
+{
+    let mut builder = UInt64Builder::new(lines.len());
+    for line in lines {
+        builder.append_value(value(line))?;
+    }
+    Ok(Arc::new(builder.finish()))
+}
+
+fn timestamp_column<F>(lines: &[RequestLogLine], value: F) -> Result<ArrayRef>
+where
+    F: Fn(&RequestLogLine) -> i64,
+{
+    let mut builder = TimestampMicrosecondBuilder::new(lines.len());
+    for line in lines {
+        builder.append_value(value(line))?;
+    }
+    Ok(Arc::new(builder.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::util::cursor::SliceableCursor;
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::log_lines;
+    use crate::output::parquet::{record_batch, ParquetOutput, SCHEMA};
+    use crate::types::{LogProcessor, RequestLogLine};
+
+    #[test]
+    fn test_parquet_round_trip() {
+        let output: ParquetOutput = serde_json::from_value(json!({
+            "bucket": "elb-logs-extracts",
+            "row_group_size": 4,
+        }))
+        .unwrap();
+        for line in log_lines() {
+            output.process_line(&line).unwrap();
+        }
+        // Written here instead of `finish`, which uploads the files
+        let files = mem::take(&mut *output.files.borrow_mut());
+        assert_eq!(1, files.len());
+        let mut file = files.into_iter().next().unwrap().1;
+        file.write_row_group().unwrap();
+        file.writer.close().unwrap();
+
+        let reader = SerializedFileReader::new(SliceableCursor::new(file.cursor.data())).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(10, metadata.file_metadata().num_rows());
+        let row_groups: Vec<i64> = metadata
+            .row_groups()
+            .iter()
+            .map(|row_group| row_group.num_rows())
+            .collect();
+        assert_eq!(vec![4, 4, 2], row_groups);
+        let columns: Vec<&str> = metadata
+            .file_metadata()
+            .schema_descr()
+            .columns()
+            .iter()
+            .map(|column| column.name())
+            .collect();
+        let fields: Vec<&str> = SCHEMA
+            .fields()
+            .iter()
+            .map(|field| field.name().as_str())
+            .collect();
+        assert_eq!(fields, columns);
+    }
+
+    #[test]
+    fn test_malformed_request() {
+        let mut line = serde_json::to_value(&log_lines()[0]).unwrap();
+        line["request"] = json!("garbage");
+        let line: RequestLogLine = serde_json::from_value(line).unwrap();
+
+        let batch = record_batch(&[line]).unwrap();
+        let method = SCHEMA.index_of("method").unwrap();
+        assert!(batch.column(method).is_null(0));
+    }
+
+    #[test]
+    fn test_zero_row_group_size() {
+        let output = serde_json::from_value::<ParquetOutput>(json!({
+            "bucket": "elb-logs-extracts",
+            "row_group_size": 0,
+        }));
+        assert!(output.unwrap_err().to_string().contains("row_group_size"));
+    }
+}
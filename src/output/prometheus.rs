@@ -0,0 +1,498 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::error;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use prost::Message;
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use schemars::JsonSchema;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+
+use crate::output::http::{http_client, send_with_retries};
+use crate::output::CachedClient;
+use crate::types::{LogProcessor, RequestLogLine};
+
+const TIMEOUT_SECONDS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PrometheusMode {
+    /// Pushes the text exposition format to `<url>/metrics/job/<job>/instance/<instance>`
+    Pushgateway,
+    /// Sends snappy compressed protobuf to a remote write endpoint
+    RemoteWrite,
+}
+
+impl Default for PrometheusMode {
+    fn default() -> Self {
+        PrometheusMode::Pushgateway
+    }
+}
+
+/// Aggregates matched lines into a request counter and a latency histogram per label set,
+/// pushing them once a log file is finished.
+///
+/// The values are running totals of the lambda container, which is told apart from the others
+/// by an `instance` label, so the counters only go up until the container is replaced.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PrometheusOutput {
+    pub url: String,
+    #[serde(default)]
+    pub mode: PrometheusMode,
+    #[serde(default = "default_job")]
+    pub job: String,
+    /// Prefix of the metrics, generating `<metric_name>_total` and `<metric_name>_latency_seconds`
+    pub metric_name: String,
+    #[serde(default = "default_labels")]
+    pub labels: Vec<String>,
+    /// Upper bounds of the latency histogram, in seconds and increasing
+    #[serde(default = "default_buckets", deserialize_with = "deserialize_buckets")]
+    pub buckets: Vec<f64>,
+    #[serde(default = "default_latency_field")]
+    pub latency_field: String,
+    #[serde(skip)]
+    series: RefCell<BTreeMap<Vec<(String, String)>, Series>>,
+    /// Lines added since the last push
+    #[serde(skip)]
+    pending: Cell<usize>,
+    #[serde(skip, default = "Uuid::new_v4")]
+    instance: Uuid,
+    #[serde(skip)]
+    client: CachedClient<Client>,
+}
+
+fn default_job() -> String {
+    "elb_logs".to_string()
+}
+
+fn default_labels() -> Vec<String> {
+    vec![
+        "elb_name".to_string(),
+        "target_group".to_string(),
+        "status_class".to_string(),
+    ]
+}
+
+fn default_buckets() -> Vec<f64> {
+    vec![
+        0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+    ]
+}
+
+/// Prometheus histograms need at least one bucket, with increasing upper bounds
+pub(crate) fn deserialize_buckets<'de, D>(deserializer: D) -> Result<Vec<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let buckets = Vec::<f64>::deserialize(deserializer)?;
+    if buckets.is_empty() {
+        return Err(D::Error::custom("buckets must not be empty"));
+    }
+    if buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(D::Error::custom(format!(
+            "buckets must be increasing, got {:?}",
+            buckets
+        )));
+    }
+    Ok(buckets)
+}
+
+fn default_latency_field() -> String {
+    "target_processing_time".to_string()
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    requests: u64,
+    /// Cumulative count of observations per bucket, like prometheus expects them
+    buckets: Vec<u64>,
+    latency_sum: f64,
+    latency_count: u64,
+}
+
+impl LogProcessor for PrometheusOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        let labels = self
+            .labels
+            .iter()
+            .map(|field| {
+                let value = log_line
+                    .field_value(field)
+                    .with_context(|| format!("unknown label field {}", field))?;
+                Ok((field.clone(), value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let latency = log_line
+            .field_value(&self.latency_field)
+            .with_context(|| format!("unknown latency field {}", self.latency_field))?
+            .parse::<f64>()
+            .ok()
+            .filter(|latency| *latency >= 0.0);
+
+        let mut series = self.series.borrow_mut();
+        let series = series.entry(labels).or_insert_with(|| Series {
+            buckets: vec![0; self.buckets.len()],
+            ..Default::default()
+        });
+        series.requests += 1;
+        self.pending.set(self.pending.get() + 1);
+        if let Some(latency) = latency {
+            series.latency_sum += latency;
+            series.latency_count += 1;
+            for (count, bound) in series.buckets.iter_mut().zip(&self.buckets) {
+                if latency <= *bound {
+                    *count += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.pending.get() == 0 {
+            return Ok(());
+        }
+        // A failed push is sent again with the next file, as the totals are kept
+        match self.mode {
+            PrometheusMode::Pushgateway => self.push_to_gateway()?,
+            PrometheusMode::RemoteWrite => self.remote_write()?,
+        }
+        self.pending.set(0);
+        Ok(())
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.pending.get()
+    }
+}
+
+impl PrometheusOutput {
+    fn push_to_gateway(&self) -> Result<()> {
+        let body = self.exposition()?;
+        let client = self
+            .client
+            .get_or_try_create(|| http_client(TIMEOUT_SECONDS))?;
+        let url = self.push_url();
+        send_with_retries(&url, || {
+            client
+                .post(&url)
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(body.clone())
+        })?;
+        Ok(())
+    }
+
+    /// Pushgateway URL of the group of this container
+    fn push_url(&self) -> String {
+        format!(
+            "{}/metrics/job/{}/instance/{}",
+            self.url.trim_end_matches('/'),
+            utf8_percent_encode(&self.job, NON_ALPHANUMERIC),
+            self.instance
+        )
+    }
+
+    /// Renders the series in the text exposition format
+    fn exposition(&self) -> Result<String> {
+        let mut body = String::new();
+        let counter = format!("{}_total", self.metric_name);
+        let histogram = format!("{}_latency_seconds", self.metric_name);
+        let series = self.series.borrow();
+
+        writeln!(body, "# TYPE {} counter", counter)?;
+        for (labels, series) in series.iter() {
+            writeln!(
+                body,
+                "{}{} {}",
+                counter,
+                format_labels(labels, None),
+                series.requests
+            )?;
+        }
+        writeln!(body, "# TYPE {} histogram", histogram)?;
+        for (labels, series) in series.iter() {
+            for (count, bound) in series.buckets.iter().zip(&self.buckets) {
+                let le = bound.to_string();
+                writeln!(
+                    body,
+                    "{}_bucket{} {}",
+                    histogram,
+                    format_labels(labels, Some(&le)),
+                    count
+                )?;
+            }
+            let all = format_labels(labels, Some("+Inf"));
+            writeln!(body, "{}_bucket{} {}", histogram, all, series.latency_count)?;
+            let labels = format_labels(labels, None);
+            writeln!(body, "{}_sum{} {}", histogram, labels, series.latency_sum)?;
+            writeln!(
+                body,
+                "{}_count{} {}",
+                histogram, labels, series.latency_count
+            )?;
+        }
+        Ok(body)
+    }
+
+    fn remote_write(&self) -> Result<()> {
+        let body = self.remote_write_body(Utc::now().timestamp_millis())?;
+        let client = self
+            .client
+            .get_or_try_create(|| http_client(TIMEOUT_SECONDS))?;
+        send_with_retries(&self.url, || {
+            client
+                .post(&self.url)
+                .header(CONTENT_TYPE, "application/x-protobuf")
+                .header(CONTENT_ENCODING, "snappy")
+                .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                .body(body.clone())
+        })?;
+        Ok(())
+    }
+
+    /// Encodes the series as a snappy compressed `WriteRequest`
+    fn remote_write_body(&self, timestamp: i64) -> Result<Vec<u8>> {
+        let counter = format!("{}_total", self.metric_name);
+        let histogram = format!("{}_latency_seconds", self.metric_name);
+        let mut request = WriteRequest::default();
+
+        for (labels, series) in self.series.borrow().iter() {
+            let mut push = |name: &str, le: Option<String>, value: f64| {
+                let mut series_labels = vec![Label {
+                    name: "__name__".to_string(),
+                    value: name.to_string(),
+                }];
+                series_labels.push(Label {
+                    name: "job".to_string(),
+                    value: self.job.clone(),
+                });
+                series_labels.push(Label {
+                    name: "instance".to_string(),
+                    value: self.instance.to_string(),
+                });
+                series_labels.extend(labels.iter().map(|(name, value)| Label {
+                    name: name.clone(),
+                    value: value.clone(),
+                }));
+                if let Some(le) = le {
+                    series_labels.push(Label {
+                        name: "le".to_string(),
+                        value: le,
+                    });
+                }
+                // Remote write expects labels sorted by name
+                series_labels.sort_by(|a, b| a.name.cmp(&b.name));
+                request.timeseries.push(TimeSeries {
+                    labels: series_labels,
+                    samples: vec![Sample { value, timestamp }],
+                });
+            };
+
+            push(&counter, None, series.requests as f64);
+            let bucket = format!("{}_bucket", histogram);
+            for (count, bound) in series.buckets.iter().zip(&self.buckets) {
+                push(&bucket, Some(bound.to_string()), *count as f64);
+            }
+            push(
+                &bucket,
+                Some("+Inf".to_string()),
+                series.latency_count as f64,
+            );
+            push(&format!("{}_sum", histogram), None, series.latency_sum);
+            push(
+                &format!("{}_count", histogram),
+                None,
+                series.latency_count as f64,
+            );
+        }
+
+        let mut protobuf = Vec::with_capacity(request.encoded_len());
+        request.encode(&mut protobuf)?;
+        Ok(snap::raw::Encoder::new()
+            .compress_vec(&protobuf)
+            .context("failed to compress remote write request")?)
+    }
+}
+
+impl Drop for PrometheusOutput {
+    fn drop(&mut self) {
+        // A gateway that is down must not panic the container, its totals are pushed next time
+        if let Err(error) = self.finish() {
+            error!("Failed to push prometheus metrics {:?}", error);
+        }
+    }
+}
+
+fn format_labels(labels: &[(String, String)], le: Option<&str>) -> String {
+    let labels = labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .chain(le.map(|le| ("le", le)))
+        .map(|(name, value)| {
+            format!(
+                "{}=\"{}\"",
+                name,
+                value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+            )
+        })
+        .collect::<Vec<_>>();
+    if labels.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Subset of the remote write protobuf messages from `prometheus/prompb`
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::{first_log_line, log_lines};
+    use crate::output::prometheus::{PrometheusOutput, WriteRequest};
+    use crate::types::LogProcessor;
+
+    /// An output holding the first two lines of the fixture, which share their labels
+    fn output(mode: &str) -> PrometheusOutput {
+        let output: PrometheusOutput = serde_json::from_value(json!({
+            "url": "http://localhost:9091/",
+            "mode": mode,
+            "job": "elb logs/prod",
+            "metric_name": "alb_requests",
+            "labels": ["status_class"],
+            "buckets": [0.1, 1.0],
+        }))
+        .unwrap();
+        for line in log_lines().iter().take(2) {
+            output.process_line(line).unwrap();
+        }
+        output
+    }
+
+    #[test]
+    fn test_exposition() {
+        let output = output("pushgateway");
+        assert_eq!(
+            format!(
+                "http://localhost:9091/metrics/job/elb%20logs%2Fprod/instance/{}",
+                output.instance
+            ),
+            output.push_url()
+        );
+
+        let body = output.exposition().unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!("# TYPE alb_requests_total counter", lines[0]);
+        assert_eq!("alb_requests_total{status_class=\"2xx\"} 2", lines[1]);
+        assert_eq!("# TYPE alb_requests_latency_seconds histogram", lines[2]);
+        assert!(lines[3]
+            .starts_with("alb_requests_latency_seconds_bucket{status_class=\"2xx\",le=\"0.1\"} "));
+        assert_eq!(
+            "alb_requests_latency_seconds_bucket{status_class=\"2xx\",le=\"+Inf\"} 2",
+            lines[5]
+        );
+        assert_eq!(
+            "alb_requests_latency_seconds_count{status_class=\"2xx\"} 2",
+            lines[7]
+        );
+        assert_eq!(8, lines.len());
+
+        // Totals keep growing with the following files
+        output.pending.set(0);
+        output.process_line(&first_log_line()).unwrap();
+        assert_eq!(1, output.pending.get());
+        assert!(output
+            .exposition()
+            .unwrap()
+            .contains("alb_requests_total{status_class=\"2xx\"} 3\n"));
+        output.pending.set(0);
+    }
+
+    #[test]
+    fn test_remote_write_body() {
+        let output = output("remote_write");
+        let body = output.remote_write_body(1_581_481_474_080).unwrap();
+        let protobuf = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        let request = WriteRequest::decode(&protobuf[..]).unwrap();
+
+        // The counter, two buckets, +Inf, the sum and the count
+        assert_eq!(6, request.timeseries.len());
+        let counter = &request.timeseries[0];
+        let labels: Vec<(&str, &str)> = counter
+            .labels
+            .iter()
+            .map(|label| (label.name.as_str(), label.value.as_str()))
+            .collect();
+        let instance = output.instance.to_string();
+        assert_eq!(
+            vec![
+                ("__name__", "alb_requests_total"),
+                ("instance", instance.as_str()),
+                ("job", "elb logs/prod"),
+                ("status_class", "2xx"),
+            ],
+            labels
+        );
+        assert_eq!(2.0, counter.samples[0].value);
+        assert_eq!(1_581_481_474_080, counter.samples[0].timestamp);
+        let infinite = &request.timeseries[3];
+        assert!(infinite
+            .labels
+            .iter()
+            .any(|label| label.name == "le" && label.value == "+Inf"));
+        assert_eq!(2.0, infinite.samples[0].value);
+        output.pending.set(0);
+    }
+
+    #[test]
+    fn test_invalid_buckets() {
+        for buckets in &[json!([]), json!([0.5, 0.1]), json!([0.1, 0.1])] {
+            let output = serde_json::from_value::<PrometheusOutput>(json!({
+                "url": "http://localhost:9091/",
+                "metric_name": "alb_requests",
+                "buckets": buckets,
+            }));
+            assert!(output.unwrap_err().to_string().contains("buckets"));
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use std::mem;
+
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, error, info};
+use rusoto_core::Region;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, S3Client, UploadPartRequest, S3,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::log_processing::csv_writer_builder;
+use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::{region_with_endpoint, CachedClient};
+use crate::s3::{get_s3_client, put_object};
+use crate::template;
+use crate::types::{LogProcessor, RequestLogLine};
+
+const BUFFER_SIZE: usize = 500;
+/// S3 refuses multipart parts smaller than 5MB, except for the last one
+const MINIMUM_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Writes matched lines as gzipped CSV files to an S3 (or S3 compatible) bucket.
+///
+/// Every processed log file generates one object per rendered `key_prefix`, so hive style
+/// partitions like `dt={dt}/elb={elb_name}/` can be used.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct S3Output {
+    pub bucket: String,
+    #[serde(default)]
+    pub key_prefix: String,
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(skip)]
+    buffer: RefCell<Vec<RequestLogLine>>,
+    #[serde(skip)]
+    uploads: RefCell<HashMap<String, ObjectUpload>>,
+    #[serde(skip)]
+    aws_region: Region,
+    #[serde(skip)]
+    client: CachedClient<S3Client>,
+}
+
+#[derive(Debug)]
+struct ObjectUpload {
+    key: String,
+    upload_id: Option<String>,
+    encoder: GzEncoder<Vec<u8>>,
+    parts: Vec<CompletedPart>,
+    /// Lines written to the object, sent once it is completed
+    lines: usize,
+}
+
+impl LogProcessor for S3Output {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        self.add_to_queue(&log_line)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        let mut result = self.flush();
+        let uploads = mem::take(&mut *self.uploads.borrow_mut());
+        // Every object is completed, or aborted, even when one of them fails
+        for (_, upload) in uploads {
+            if let Err(error) = self.complete_upload(upload) {
+                error!("Failed to upload extract {:?}", error);
+                result = result.and(Err(error));
+            }
+        }
+        result
+    }
+
+    fn buffered_lines(&self) -> usize {
+        let uploading: usize = self
+            .uploads
+            .borrow()
+            .values()
+            .map(|upload| upload.lines)
+            .sum();
+        self.buffer_len() + uploading
+    }
+}
+
+impl BufferedLogProcessor for S3Output {
+    fn maximum_buffer_size(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    fn buffer_clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+
+    fn push_to_buffer(&self, log_line: RequestLogLine) {
+        self.buffer.borrow_mut().push(log_line);
+    }
+
+    fn process_log_lines(&self) -> Result<()> {
+        let mut uploads = self.uploads.borrow_mut();
+        for line in self.buffer.borrow().iter() {
+            let prefix = template::render_key(&self.key_prefix, line)?;
+            let upload = uploads
+                .entry(prefix.clone())
+                .or_insert_with(|| ObjectUpload {
+                    key: format!("{}{}.csv.gz", prefix, Uuid::new_v4()),
+                    upload_id: None,
+                    encoder: GzEncoder::new(Vec::new(), Compression::default()),
+                    parts: Vec::new(),
+                    lines: 0,
+                });
+
+            let mut buffer = Cursor::new(Vec::new());
+            csv_writer_builder()
+                .from_writer(buffer.by_ref())
+                .serialize(line)?;
+            upload.encoder.write_all(buffer.get_ref())?;
+            upload.lines += 1;
+
+            if upload.encoder.get_ref().len() >= MINIMUM_PART_SIZE {
+                let data = mem::take(upload.encoder.get_mut());
+                if let Err(error) = self.upload_part(upload, data) {
+                    // The object misses a part, it can't be completed anymore
+                    if let Some(upload) = uploads.remove(&prefix) {
+                        self.abort_upload(&upload);
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl S3Output {
+    fn upload_part(&self, upload: &mut ObjectUpload, data: Vec<u8>) -> Result<()> {
+        let client = self.s3_client();
+        let upload_id = match &upload.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let request = CreateMultipartUploadRequest {
+                    bucket: self.bucket.clone(),
+                    key: upload.key.clone(),
+                    content_type: Some("text/csv".to_string()),
+                    content_encoding: Some("gzip".to_string()),
+                    ..Default::default()
+                };
+                let response = client
+                    .create_multipart_upload(request)
+                    .sync()
+                    .with_context(|| format!("failed to start upload of {}", upload.key))?;
+                let upload_id = response.upload_id.context("no upload id returned by s3")?;
+                upload.upload_id = Some(upload_id.clone());
+                upload_id
+            }
+        };
+
+        let part_number = upload.parts.len() as i64 + 1;
+        debug!(
+            "Uploading part {} of s3://{}/{} with {} bytes",
+            part_number,
+            self.bucket,
+            upload.key,
+            data.len()
+        );
+        let request = UploadPartRequest {
+            bucket: self.bucket.clone(),
+            key: upload.key.clone(),
+            upload_id,
+            part_number,
+            content_length: Some(data.len() as i64),
+            body: Some(data.into()),
+            ..Default::default()
+        };
+        let response = client
+            .upload_part(request)
+            .sync()
+            .with_context(|| format!("failed to upload part {} of {}", part_number, upload.key))?;
+        upload.parts.push(CompletedPart {
+            e_tag: response.e_tag,
+            part_number: Some(part_number),
+        });
+        Ok(())
+    }
+
+    fn complete_upload(&self, mut upload: ObjectUpload) -> Result<()> {
+        let data = mem::replace(
+            &mut upload.encoder,
+            GzEncoder::new(Vec::new(), Compression::default()),
+        )
+        .finish()?;
+
+        if upload.upload_id.is_none() {
+            return put_object(
+                &self.s3_client(),
+                &self.bucket,
+                &upload.key,
+                data,
+                "text/csv",
+                Some("gzip"),
+            );
+        }
+
+        let result = self.upload_part(&mut upload, data).and_then(|_| {
+            let request = CompleteMultipartUploadRequest {
+                bucket: self.bucket.clone(),
+                key: upload.key.clone(),
+                upload_id: upload.upload_id.clone().unwrap(),
+                multipart_upload: Some(CompletedMultipartUpload {
+                    parts: Some(upload.parts.clone()),
+                }),
+                ..Default::default()
+            };
+            self.s3_client()
+                .complete_multipart_upload(request)
+                .sync()
+                .with_context(|| format!("failed to complete upload of {}", upload.key))
+        });
+        if let Err(error) = result {
+            self.abort_upload(&upload);
+            return Err(error);
+        }
+
+        info!("Uploaded s3://{}/{}", self.bucket, upload.key);
+        Ok(())
+    }
+
+    /// Deletes the parts of an upload that failed, S3 keeps and bills them otherwise
+    fn abort_upload(&self, upload: &ObjectUpload) {
+        let upload_id = match &upload.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => return,
+        };
+        let request = AbortMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: upload.key.clone(),
+            upload_id,
+            ..Default::default()
+        };
+        if let Err(error) = self.s3_client().abort_multipart_upload(request).sync() {
+            error!("Failed to abort upload of {}: {:?}", upload.key, error);
+        }
+    }
+
+    fn s3_client(&self) -> S3Client {
+        self.client.get_or_create(|| {
+            get_s3_client(&region_with_endpoint(&self.aws_region, &self.endpoint))
+        })
+    }
+}
+
+impl Drop for S3Output {
+    fn drop(&mut self) {
+        self.finish().expect("failed to upload extracts to s3");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use rusoto_core::Region;
+    use rusoto_s3::{CreateBucketRequest, GetObjectRequest, ListObjectsV2Request, S3};
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::log_lines;
+    use crate::output::buffered_trait::BufferedLogProcessor;
+    use crate::output::region_with_endpoint;
+    use crate::output::s3::S3Output;
+    use crate::s3::get_s3_client;
+    use crate::types::LogProcessor;
+
+    #[test]
+    fn test_object_keys() {
+        let output: S3Output = serde_json::from_value(json!({
+            "bucket": "elb-logs-extracts",
+            "key_prefix": "dt={dt}/hour={HH}/",
+        }))
+        .unwrap();
+        for line in log_lines() {
+            output.push_to_buffer(line);
+        }
+        output.process_log_lines().unwrap();
+        output.buffer_clear();
+
+        let mut uploads: Vec<_> = output.uploads.borrow_mut().drain().collect();
+        uploads.sort_by(|a, b| a.0.cmp(&b.0));
+        let prefixes: Vec<&str> = uploads.iter().map(|(prefix, _)| prefix.as_str()).collect();
+        assert_eq!(
+            vec!["dt=2020-02-12/hour=04/", "dt=2020-02-12/hour=06/"],
+            prefixes
+        );
+        for (prefix, upload) in &uploads {
+            assert!(upload.key.starts_with(prefix.as_str()));
+            assert!(upload.key.ends_with(".csv.gz"));
+            assert!(upload.upload_id.is_none());
+        }
+    }
+
+    /// Needs a local MinIO, e.g. `docker run -p 9000:9000 -e MINIO_ROOT_USER=minio
+    /// -e MINIO_ROOT_PASSWORD=minio123 minio/minio server /data`, with `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY` set to the same credentials
+    #[test]
+    #[ignore]
+    fn test_minio_round_trip() {
+        let endpoint =
+            env::var("MINIO_ENDPOINT").unwrap_or_else(|_| "http://localhost:9000".into());
+        let bucket = "elb-logs-extracts";
+        let prefix = format!("test/{}/", uuid::Uuid::new_v4());
+        let client = get_s3_client(&region_with_endpoint(
+            &Region::default(),
+            &Some(endpoint.clone()),
+        ));
+        // Fails when the bucket exists already
+        let _ = client
+            .create_bucket(CreateBucketRequest {
+                bucket: bucket.to_string(),
+                ..Default::default()
+            })
+            .sync();
+
+        let output: S3Output = serde_json::from_value(json!({
+            "bucket": bucket,
+            "key_prefix": format!("{}hour={{HH}}/", prefix),
+            "endpoint": endpoint,
+        }))
+        .unwrap();
+        for line in log_lines() {
+            output.process_line(&line).unwrap();
+        }
+        output.finish().unwrap();
+
+        let objects = client
+            .list_objects_v2(ListObjectsV2Request {
+                bucket: bucket.to_string(),
+                prefix: Some(prefix),
+                ..Default::default()
+            })
+            .sync()
+            .unwrap()
+            .contents
+            .unwrap_or_default();
+        assert_eq!(2, objects.len());
+        let mut lines = 0;
+        for object in objects {
+            let body = client
+                .get_object(GetObjectRequest {
+                    bucket: bucket.to_string(),
+                    key: object.key.unwrap(),
+                    ..Default::default()
+                })
+                .sync()
+                .unwrap()
+                .body
+                .unwrap();
+            let mut content = String::new();
+            GzDecoder::new(body.into_blocking_read())
+                .read_to_string(&mut content)
+                .unwrap();
+            lines += content.lines().count();
+        }
+        assert_eq!(10, lines);
+    }
+}
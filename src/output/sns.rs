@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+use rusoto_core::Region;
+use rusoto_sns::{PublishInput, Sns, SnsClient};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::output::format::notification_body;
+use crate::output::{region_with_endpoint, CachedClient};
+use crate::template;
+use crate::types::{LogProcessor, RequestLogLine};
+
+/// Publishes every matched line to a topic, meant for rare and high signal events
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SnsOutput {
+    pub topic_arn: String,
+    /// Template rendered into the `message` attribute of the JSON body
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Template used as the subject, shown by email subscriptions
+    #[serde(default)]
+    pub subject: Option<String>,
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(skip)]
+    aws_region: Region,
+    #[serde(skip)]
+    client: CachedClient<SnsClient>,
+}
+
+impl LogProcessor for SnsOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        let input = self.publish_input(log_line)?;
+        self.client
+            .get_or_create(|| {
+                SnsClient::new(region_with_endpoint(&self.aws_region, &self.endpoint))
+            })
+            .publish(input)
+            .sync()
+            .with_context(|| format!("error publishing message to {}", self.topic_arn))?;
+        Ok(())
+    }
+}
+
+impl SnsOutput {
+    fn publish_input(&self, line: &RequestLogLine) -> Result<PublishInput> {
+        let subject = match &self.subject {
+            Some(subject) => Some(template::render(subject, line)?),
+            None => None,
+        };
+        Ok(PublishInput {
+            message: notification_body(&self.message, line)?,
+            subject,
+            topic_arn: Some(self.topic_arn.clone()),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::first_log_line;
+    use crate::output::sns::SnsOutput;
+
+    #[test]
+    fn test_publish_input() {
+        let output: SnsOutput = serde_json::from_value(json!({
+            "topic_arn": "arn:aws:sns:eu-central-1:123456789012:alerts",
+            "message": "{elb_status_code} for {path}",
+            "subject": "{status_class} on {elb_name}",
+        }))
+        .unwrap();
+        let line = first_log_line();
+        let input = output.publish_input(&line).unwrap();
+        assert_eq!(
+            Some(format!("{} on {}", line.status_class(), line.elb_name)),
+            input.subject
+        );
+        let body: serde_json::Value = serde_json::from_str(&input.message).unwrap();
+        assert_eq!(
+            format!("{} for {}", line.elb_status_code, line.request().path),
+            body["message"]
+        );
+        assert_eq!(
+            Some("arn:aws:sns:eu-central-1:123456789012:alerts".to_string()),
+            input.topic_arn
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use std::cell::RefCell;
+use std::thread::sleep;
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use rusoto_core::Region;
+use rusoto_sqs::{SendMessageBatchRequest, SendMessageBatchRequestEntry, Sqs, SqsClient};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use smallvec::SmallVec;
+
+use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::format::notification_body;
+use crate::output::{
+    backoff, deserialize_field_name, region_with_endpoint, CachedClient, MAXIMUM_RETRIES,
+};
+use crate::types::{LogProcessor, RequestLogLine};
+
+/// SQS accepts at most 10 messages per SendMessageBatch call
+const SQS_BATCH_SIZE: usize = 10;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SqsOutput {
+    pub queue_url: String,
+    /// Template rendered into the `message` attribute of the JSON body
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Field used as the message group id, required by FIFO queues
+    #[serde(default, deserialize_with = "deserialize_field_name")]
+    pub message_group_field: Option<String>,
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(skip)]
+    buffer: RefCell<SmallVec<[RequestLogLine; SQS_BATCH_SIZE]>>,
+    #[serde(skip)]
+    aws_region: Region,
+    #[serde(skip)]
+    client: CachedClient<SqsClient>,
+}
+
+impl LogProcessor for SqsOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        self.add_to_queue(&log_line)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
+}
+
+impl BufferedLogProcessor for SqsOutput {
+    fn maximum_buffer_size(&self) -> usize {
+        SQS_BATCH_SIZE
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    fn buffer_clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+
+    fn push_to_buffer(&self, log_line: RequestLogLine) {
+        self.buffer.borrow_mut().push(log_line);
+    }
+
+    fn process_log_lines(&self) -> Result<()> {
+        let entries = self
+            .buffer
+            .borrow()
+            .iter()
+            .enumerate()
+            .map(|(index, line)| self.log_line_to_entry(index, line))
+            .collect::<Result<Vec<_>>>()
+            .context("error converting log line to sqs message")?;
+
+        let client = self.client.get_or_create(|| {
+            SqsClient::new(region_with_endpoint(&self.aws_region, &self.endpoint))
+        });
+        let mut pending = entries;
+        for attempt in 0..=MAXIMUM_RETRIES {
+            if attempt > 0 {
+                sleep(backoff(attempt));
+            }
+            let request = SendMessageBatchRequest {
+                entries: pending.clone(),
+                queue_url: self.queue_url.clone(),
+            };
+            let response = client
+                .send_message_batch(request)
+                .sync()
+                .with_context(|| format!("error sending messages to {}", self.queue_url))?;
+            if response.failed.is_empty() {
+                return Ok(());
+            }
+
+            pending.retain(|entry| response.failed.iter().any(|failed| failed.id == entry.id));
+            warn!(
+                "{} messages were rejected by {}, retrying",
+                pending.len(),
+                self.queue_url
+            );
+        }
+        bail!(
+            "{} messages could not be sent to {}",
+            pending.len(),
+            self.queue_url
+        )
+    }
+}
+
+impl SqsOutput {
+    fn log_line_to_entry(
+        &self,
+        index: usize,
+        line: &RequestLogLine,
+    ) -> Result<SendMessageBatchRequestEntry> {
+        let message_group_id = match &self.message_group_field {
+            Some(field) => Some(
+                line.field_value(field)
+                    .with_context(|| format!("unknown message group field {}", field))?,
+            ),
+            None => None,
+        };
+        let message_body = notification_body(&self.message, line)?;
+        // Derived from the content, so a retried batch is deduplicated by the queue
+        let message_deduplication_id = if self.queue_url.ends_with(".fifo") {
+            Some(deduplication_id(&message_body))
+        } else {
+            None
+        };
+        Ok(SendMessageBatchRequestEntry {
+            id: index.to_string(),
+            message_body,
+            message_group_id,
+            message_deduplication_id,
+            ..Default::default()
+        })
+    }
+}
+
+/// SHA-256 of the message body, which stays the same across builds, unlike `DefaultHasher`, so a
+/// retry sent by a redeployed lambda is still deduplicated
+fn deduplication_id(message_body: &str) -> String {
+    format!("{:x}", Sha256::digest(message_body.as_bytes()))
+}
+
+impl Drop for SqsOutput {
+    fn drop(&mut self) {
+        self.flush().expect("failed to flush sqs messages");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::first_log_line;
+    use crate::output::sqs::{deduplication_id, SqsOutput};
+
+    #[test]
+    fn test_fifo_entry() {
+        let output: SqsOutput = serde_json::from_value(json!({
+            "queue_url": "https://sqs.eu-central-1.amazonaws.com/123456789012/alerts.fifo",
+            "message": "{elb_status_code} {path}",
+            "message_group_field": "target_group",
+        }))
+        .unwrap();
+        let line = first_log_line();
+        let entry = output.log_line_to_entry(3, &line).unwrap();
+        assert_eq!("3", entry.id);
+        assert_eq!(
+            Some(line.target_group().to_string()),
+            entry.message_group_id
+        );
+        let body: serde_json::Value = serde_json::from_str(&entry.message_body).unwrap();
+        assert_eq!(line.trace_id, body["line"]["trace_id"]);
+        let deduplication_id = entry.message_deduplication_id.unwrap();
+        assert_eq!(
+            Some(deduplication_id),
+            output
+                .log_line_to_entry(0, &line)
+                .unwrap()
+                .message_deduplication_id
+        );
+    }
+
+    #[test]
+    fn test_standard_entry() {
+        let output: SqsOutput = serde_json::from_value(json!({
+            "queue_url": "https://sqs.eu-central-1.amazonaws.com/123456789012/alerts",
+        }))
+        .unwrap();
+        let entry = output.log_line_to_entry(0, &first_log_line()).unwrap();
+        assert_eq!(None, entry.message_group_id);
+        assert_eq!(None, entry.message_deduplication_id);
+    }
+
+    #[test]
+    fn test_deduplication_id() {
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            deduplication_id("abc")
+        );
+    }
+
+    #[test]
+    fn test_unknown_message_group_field() {
+        let output = serde_json::from_value::<SqsOutput>(json!({
+            "queue_url": "https://sqs.eu-central-1.amazonaws.com/123456789012/alerts.fifo",
+            "message_group_field": "target_grup",
+        }));
+        assert!(output.unwrap_err().to_string().contains("target_grup"));
+    }
+}
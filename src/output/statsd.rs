@@ -0,0 +1,318 @@
+use std::cell::RefCell;
+use std::net::UdpSocket;
+
+use anyhow::{Context, Result};
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use smallvec::SmallVec;
+
+use crate::output::buffered_trait::BufferedLogProcessor;
+use crate::output::{deserialize_field_names, deserialize_latency_fields, split_batches};
+use crate::types::{LogProcessor, RequestLogLine};
+
+const STATSD_BATCH_SIZE: usize = 20;
+/// Keeps datagrams under the usual MTU so they are not fragmented
+const MAXIMUM_DATAGRAM_SIZE: usize = 1432;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LatencyType {
+    Timing,
+    Histogram,
+    Distribution,
+}
+
+impl Default for LatencyType {
+    fn default() -> Self {
+        LatencyType::Timing
+    }
+}
+
+impl LatencyType {
+    fn statsd_type(self) -> &'static str {
+        match self {
+            LatencyType::Timing => "ms",
+            LatencyType::Histogram => "h",
+            LatencyType::Distribution => "d",
+        }
+    }
+}
+
+/// Sends a counter per matched line (and optionally its latencies) to a StatsD or DogStatsD
+/// agent, the same way `CloudwatchMetricOutput` does for CloudWatch
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdOutput {
+    #[serde(default = "default_address")]
+    pub address: String,
+    pub metric_name: String,
+    /// Latency fields (in seconds on the logs) sent in milliseconds as `<metric_name>.<field>`
+    #[serde(default, deserialize_with = "deserialize_latency_fields")]
+    pub latency_fields: Vec<String>,
+    #[serde(default)]
+    pub latency_type: LatencyType,
+    /// Fields sent as DogStatsD tags
+    #[serde(default = "default_tags", deserialize_with = "deserialize_field_names")]
+    pub tags: Vec<String>,
+    /// Fraction of the lines sent, greater than 0 and at most 1
+    #[serde(default, deserialize_with = "deserialize_sample_rate")]
+    pub sample_rate: Option<f64>,
+    #[serde(skip)]
+    buffer: RefCell<SmallVec<[RequestLogLine; STATSD_BATCH_SIZE]>>,
+    #[serde(skip)]
+    socket: RefCell<Option<UdpSocket>>,
+}
+
+fn default_address() -> String {
+    "127.0.0.1:8125".to_string()
+}
+
+fn default_tags() -> Vec<String> {
+    vec!["elb_name".to_string(), "target_group".to_string()]
+}
+
+fn deserialize_sample_rate<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(rate) if rate > 0.0 && rate <= 1.0 => Ok(Some(rate)),
+        Some(rate) => Err(D::Error::custom(format!(
+            "sample_rate must be greater than 0 and at most 1, got {}",
+            rate
+        ))),
+        None => Ok(None),
+    }
+}
+
+impl LogProcessor for StatsdOutput {
+    fn process_line(&self, log_line: &RequestLogLine) -> Result<()> {
+        if let Some(sample_rate) = self.sample_rate {
+            if rand::thread_rng().gen::<f64>() >= sample_rate {
+                return Ok(());
+            }
+        }
+        self.add_to_queue(&log_line)?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn buffered_lines(&self) -> usize {
+        self.buffer_len()
+    }
+}
+
+impl BufferedLogProcessor for StatsdOutput {
+    fn maximum_buffer_size(&self) -> usize {
+        STATSD_BATCH_SIZE
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    fn buffer_clear(&self) {
+        self.buffer.borrow_mut().clear()
+    }
+
+    fn push_to_buffer(&self, log_line: RequestLogLine) {
+        self.buffer.borrow_mut().push(log_line);
+    }
+
+    fn process_log_lines(&self) -> Result<()> {
+        let metrics = self
+            .buffer
+            .borrow()
+            .iter()
+            .map(|line| self.log_line_to_metrics(line))
+            .collect::<Result<Vec<_>>>()
+            .context("error converting log line to statsd metrics")?
+            .concat();
+
+        let mut socket = self.socket.borrow_mut();
+        if socket.is_none() {
+            let new_socket = UdpSocket::bind("0.0.0.0:0")?;
+            new_socket
+                .connect(&self.address)
+                .with_context(|| format!("invalid statsd address {}", self.address))?;
+            *socket = Some(new_socket);
+        }
+        let socket = socket.as_ref().unwrap();
+
+        // One byte per metric accounts for the line break joining them
+        for datagram in split_batches(metrics, usize::MAX, MAXIMUM_DATAGRAM_SIZE, |metric| {
+            metric.len() + 1
+        }) {
+            socket.send(datagram.join("\n").as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl StatsdOutput {
+    fn log_line_to_metrics(&self, line: &RequestLogLine) -> Result<Vec<String>> {
+        let suffix = self.metric_suffix(line)?;
+        let mut metrics = vec![format!("{}:1|c{}", self.metric_name, suffix)];
+
+        for field in &self.latency_fields {
+            let value = line
+                .field_value(field)
+                .with_context(|| format!("unknown latency field {}", field))?;
+            // AWS logs -1 when the target could not be reached
+            match value.parse::<f64>() {
+                Ok(seconds) if seconds >= 0.0 => metrics.push(format!(
+                    "{}.{}:{}|{}{}",
+                    self.metric_name,
+                    field,
+                    seconds * 1000.0,
+                    self.latency_type.statsd_type(),
+                    suffix
+                )),
+                _ => continue,
+            }
+        }
+        Ok(metrics)
+    }
+
+    /// Sample rate and tags, shared by all the metrics of a line
+    fn metric_suffix(&self, line: &RequestLogLine) -> Result<String> {
+        let mut suffix = String::new();
+        if let Some(sample_rate) = self.sample_rate {
+            suffix.push_str(&format!("|@{}", sample_rate));
+        }
+        if !self.tags.is_empty() {
+            let tags = self
+                .tags
+                .iter()
+                .map(|field| {
+                    let value = line
+                        .field_value(field)
+                        .with_context(|| format!("unknown tag field {}", field))?;
+                    Ok(format!("{}:{}", field, tag_value(&value)))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            suffix.push_str(&format!("|#{}", tags.join(",")));
+        }
+        Ok(suffix)
+    }
+}
+
+/// Replaces the characters that delimit tags and metrics in a datagram
+fn tag_value(value: &str) -> String {
+    value.replace(&[',', '|', '#', '\n'][..], "_")
+}
+
+impl Drop for StatsdOutput {
+    fn drop(&mut self) {
+        self.flush().expect("failed to flush statsd metrics");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::log_processing::fixtures::first_log_line;
+    use crate::output::buffered_trait::BufferedLogProcessor;
+    use crate::output::statsd::StatsdOutput;
+    use crate::types::{LogProcessor, MaybeNumber, RequestLogLine};
+
+    /// Flushes a line to a local socket and returns the datagram received
+    fn packet(config: serde_json::Value, line: &RequestLogLine) -> String {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut config = config;
+        config["address"] = receiver.local_addr().unwrap().to_string().into();
+        let output: StatsdOutput = serde_json::from_value(config).unwrap();
+        output.process_line(line).unwrap();
+        output.flush().unwrap();
+
+        let mut buffer = [0; 2048];
+        let size = receiver.recv(&mut buffer).unwrap();
+        String::from_utf8(buffer[..size].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_metric_lines() {
+        let tags = "#elb_name:app/private-ecs-on-production/8cb653e6ebead26b,\
+                    target_group:internal-service-production";
+        assert_eq!(
+            format!(
+                "alb.requests:1|c|{}\nalb.requests.target_processing_time:250|ms|{}",
+                tags, tags
+            ),
+            packet(
+                json!({
+                    "metric_name": "alb.requests",
+                    "latency_fields": ["target_processing_time"],
+                }),
+                &first_log_line()
+            )
+        );
+        assert_eq!(
+            "alb.requests:1|c|@1|#elb_status_code:200",
+            packet(
+                json!({
+                    "metric_name": "alb.requests",
+                    "tags": ["elb_status_code"],
+                    "sample_rate": 1.0,
+                }),
+                &first_log_line()
+            )
+        );
+    }
+
+    #[test]
+    fn test_tag_encoding() {
+        let mut line = first_log_line();
+        line.user_agent = "Mozilla/5.0 (compatible, bot|#1)".to_string();
+        line.target_processing_time = MaybeNumber::Number(-1.0);
+        assert_eq!(
+            "alb.requests:1|c|#user_agent:Mozilla/5.0 (compatible_ bot__1)",
+            packet(
+                json!({
+                    "metric_name": "alb.requests",
+                    "latency_fields": ["target_processing_time"],
+                    "tags": ["user_agent"],
+                }),
+                &line
+            )
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let error = |config: serde_json::Value| {
+            serde_json::from_value::<StatsdOutput>(config)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error(json!({
+            "metric_name": "alb.requests",
+            "tags": ["elb_name", "target_grup"],
+        }))
+        .contains("target_grup"));
+        assert!(error(json!({
+            "metric_name": "alb.requests",
+            "latency_fields": ["sent_bytes"],
+        }))
+        .contains("not a latency field"));
+        for rate in &[0.0, 1.5, -0.5] {
+            assert!(error(json!({
+                "metric_name": "alb.requests",
+                "sample_rate": rate,
+            }))
+            .contains("sample_rate"));
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use anyhow::Result;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::types::{LogProcessor, RequestLogLine};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct StdoutOutput {
     #[serde(skip, default = "crate::log_processing::csv_writer_builder")]
     writer: csv::WriterBuilder,
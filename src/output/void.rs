@@ -1,9 +1,10 @@
 use anyhow::Error;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::types::{LogProcessor, RequestLogLine};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct VoidOutput;
 impl LogProcessor for VoidOutput {
     fn process_line(&self, _log_line: &RequestLogLine) -> Result<(), Error> {
@@ -1,8 +1,15 @@
+use std::rc::Rc;
 use std::str::FromStr;
 
+use log::error;
+use rusoto_core::Region;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Metadata, Schema, SchemaObject};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use wirefilter::Scheme;
 
+use crate::interpolation::interpolate;
 use crate::output::OutputType;
 
 lazy_static::lazy_static! {
@@ -13,14 +20,90 @@ lazy_static::lazy_static! {
     };
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Fields of `SCHEME` and their types, listed in the JSON Schema of the filters
+pub const FILTER_FIELDS: [(&str, &str); 3] = [
+    ("elb_status_code", "Int"),
+    ("user_agent", "Bytes"),
+    ("target_group_arn", "Bytes"),
+];
+
+fn filter_schema(_: &mut SchemaGenerator) -> Schema {
+    let fields: Vec<String> = FILTER_FIELDS
+        .iter()
+        .map(|(name, kind)| format!("{} ({})", name, kind))
+        .collect();
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        metadata: Some(Box::new(Metadata {
+            description: Some(format!(
+                "Wirefilter expression selecting the lines, e.g. `elb_status_code >= 500`. \
+                 Available fields: {}",
+                fields.join(", ")
+            )),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Pipeline {
+    /// Used to identify the pipeline in logs and statistics
+    #[serde(default)]
+    pub name: Option<String>,
+    #[schemars(schema_with = "filter_schema")]
     pub filter: String,
-    pub output: OutputType,
+    /// Kept for backward compatibility, new pipelines should use `outputs`
+    #[serde(default)]
+    pub output: Option<OutputType>,
+    #[serde(default)]
+    pub outputs: Vec<OutputType>,
+    /// Lines matched by this pipeline are not evaluated by the following ones
+    #[serde(default)]
+    pub stop: bool,
+}
+
+/// How lines are routed when more than one pipeline matches them
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingMode {
+    /// Every matching pipeline gets the line, unless one of them has `stop` set
+    All,
+    /// Only the first matching pipeline gets the line
+    FirstMatch,
+}
+
+impl Default for RoutingMode {
+    fn default() -> Self {
+        RoutingMode::All
+    }
 }
 
 impl Pipeline {
-    pub fn get_filter(&self) -> wirefilter::Filter {
+    /// All the outputs every matched line is sent to
+    pub fn outputs(&self) -> impl Iterator<Item = &OutputType> {
+        self.output.iter().chain(self.outputs.iter())
+    }
+
+    /// The configured name, or the position of the pipeline when it has none
+    pub fn display_name(&self, index: usize) -> String {
+        self.name.clone().unwrap_or_else(|| format!("#{}", index))
+    }
+
+    /// Checks the pipeline has outputs and a filter that can be compiled
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.outputs().next().is_none() {
+            anyhow::bail!("pipeline {:?} has no outputs", self.filter);
+        }
+        if let Err(error) = SCHEME.parse(self.filter.as_str()) {
+            anyhow::bail!("invalid filter {:?}: {}", self.filter, error);
+        }
+        Ok(())
+    }
+
+    pub fn get_filter(&self) -> wirefilter::Filter<'static> {
         let ast = SCHEME
             .parse(self.filter.as_str())
             .unwrap_or_else(|_| panic!("Failed to parse the input filter: {:?}", self.filter));
@@ -29,14 +112,43 @@ impl Pipeline {
 }
 
 #[derive(Debug)]
-pub struct Pipelines(Vec<Pipeline>);
+pub struct Pipelines {
+    mode: RoutingMode,
+    pipelines: Vec<Pipeline>,
+}
 
 impl Pipelines {
     pub fn new(pipelines: Vec<Pipeline>) -> Self {
-        Self(pipelines)
+        Self {
+            mode: RoutingMode::default(),
+            pipelines,
+        }
+    }
+    pub fn with_mode(mut self, mode: RoutingMode) -> Self {
+        self.mode = mode;
+        self
     }
     pub fn inner(&self) -> &Vec<Pipeline> {
-        &self.0
+        &self.pipelines
+    }
+    pub fn mode(&self) -> RoutingMode {
+        self.mode
+    }
+}
+
+/// Pipelines can be configured as a plain list or together with a routing mode
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PipelinesConfig {
+    #[serde(default)]
+    mode: RoutingMode,
+    pipelines: Vec<Pipeline>,
+}
+
+impl Pipelines {
+    /// Parses the JSON pipelines after replacing their `${...}` references
+    pub(crate) fn interpolated(json: &str, region: &Region) -> anyhow::Result<Self> {
+        interpolate(json, region)?.parse()
     }
 }
 
@@ -44,14 +156,79 @@ impl FromStr for Pipelines {
     type Err = anyhow::Error;
 
     fn from_str(json: &str) -> Result<Self, Self::Err> {
-        Ok(Self(serde_json::from_str(json)?))
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        let PipelinesConfig { mode, pipelines } = if value.is_array() {
+            PipelinesConfig {
+                mode: RoutingMode::default(),
+                pipelines: serde_json::from_value(value)?,
+            }
+        } else {
+            serde_json::from_value(value)?
+        };
+        for pipeline in &pipelines {
+            pipeline.validate()?;
+        }
+        Ok(Self { mode, pipelines })
     }
 }
 
-pub fn compile_pipelines(pipelines: &Pipelines) -> Vec<(&Pipeline, wirefilter::Filter)> {
-    pipelines
-        .inner()
-        .iter()
-        .map(|pipeline| (pipeline, pipeline.get_filter()))
-        .collect()
+/// Pipelines with their filters compiled, owning them so they can be kept between invocations
+pub struct CompiledPipelines {
+    pipelines: Rc<Pipelines>,
+    filters: Vec<wirefilter::Filter<'static>>,
+}
+
+impl CompiledPipelines {
+    pub fn mode(&self) -> RoutingMode {
+        self.pipelines.mode()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Pipeline, &wirefilter::Filter<'static>)> {
+        self.pipelines.inner().iter().zip(self.filters.iter())
+    }
+
+    pub fn raw(&self) -> &Pipelines {
+        &self.pipelines
+    }
+
+    /// Flushes everything the outputs still buffer, trying all of them before returning an error
+    pub fn finish(&self) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for output in self.pipelines.inner().iter().flat_map(Pipeline::outputs) {
+            if let Err(error) = output.get_log_processor().finish() {
+                error!("Failed to flush output {:?}", error);
+                result = Err(error);
+            }
+        }
+        result
+    }
+}
+
+pub fn compile_pipelines<P: Into<Rc<Pipelines>>>(pipelines: P) -> CompiledPipelines {
+    let pipelines = pipelines.into();
+    let filters = pipelines.inner().iter().map(Pipeline::get_filter).collect();
+    CompiledPipelines { pipelines, filters }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipelines::{FILTER_FIELDS, SCHEME};
+
+    #[test]
+    fn test_filter_fields_match_scheme() {
+        for (name, kind) in FILTER_FIELDS.iter() {
+            let filter = match *kind {
+                "Int" => format!("{} == 0", name),
+                "Bytes" => format!("{} == \"\"", name),
+                _ => panic!("unexpected type {} of {}", kind, name),
+            };
+            assert!(
+                SCHEME.parse(&filter).is_ok(),
+                "{} is not a {} field of the scheme",
+                name,
+                kind
+            );
+        }
+        assert!(SCHEME.parse("unknown_field == 0").is_err());
+    }
 }
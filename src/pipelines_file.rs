@@ -0,0 +1,327 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use rusoto_core::Region;
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::interpolation::interpolate;
+use crate::pipelines::{Pipeline, Pipelines, RoutingMode};
+
+/// A pipelines file, which can also be a plain list of pipelines
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct PipelinesFile {
+    #[serde(default)]
+    mode: Option<RoutingMode>,
+    /// Other files loaded before the pipelines of this one, relative to it
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
+    pipelines: Vec<Pipeline>,
+}
+
+/// Root of the JSON Schema, covering every way of configuring pipelines
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum PipelinesSchema {
+    List(Vec<Pipeline>),
+    File(PipelinesFile),
+}
+
+pub(crate) fn json_schema() -> RootSchema {
+    schema_for!(PipelinesSchema)
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(Format::Json),
+            Some("yaml") | Some("yml") => Ok(Format::Yaml),
+            Some("toml") => Ok(Format::Toml),
+            _ => bail!(
+                "unsupported pipelines file {}, expected a .json, .yaml, .yml or .toml extension",
+                path.display()
+            ),
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, content: &str) -> Result<T> {
+        // All the three formats report the line and column of the error in their message
+        Ok(match self {
+            Format::Json => serde_json::from_str(content)?,
+            Format::Yaml => serde_yaml::from_str(content)?,
+            Format::Toml => toml::from_str(content)?,
+        })
+    }
+
+    fn is_list(self, content: &str) -> Result<bool> {
+        Ok(match self {
+            Format::Json => content.trim_start().starts_with('['),
+            Format::Yaml => serde_yaml::from_str::<serde_yaml::Value>(content)?.is_sequence(),
+            // The top level of a TOML document is always a table
+            Format::Toml => false,
+        })
+    }
+}
+
+/// Loads the pipelines from a JSON, YAML or TOML file, or from every `*.yaml` file of a
+/// directory, merged in file name order.
+pub(crate) fn load(path: &Path, region: &Region) -> Result<Pipelines> {
+    let mut loaded = Loaded {
+        region: region.clone(),
+        ..Default::default()
+    };
+    if path.is_dir() {
+        let mut files = fs::read_dir(path)
+            .with_context(|| format!("failed to list {}", path.display()))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        files.retain(|file| {
+            file.extension()
+                .map_or(false, |extension| extension == "yaml" || extension == "yml")
+        });
+        files.sort();
+        if files.is_empty() {
+            bail!("no *.yaml files found in {}", path.display());
+        }
+        for file in files {
+            loaded.load_file(&file)?;
+        }
+    } else {
+        loaded.load_file(path)?;
+    }
+
+    if loaded.pipelines.is_empty() {
+        bail!("no pipelines found in {}", path.display());
+    }
+    Ok(Pipelines::new(loaded.pipelines).with_mode(loaded.mode.unwrap_or_default()))
+}
+
+/// Parses a single document fetched from somewhere else than the local file system, using the
+/// extension of `name` to pick the format and defaulting to JSON.
+pub(crate) fn parse_document(content: &str, name: &str, region: &Region) -> Result<Pipelines> {
+    let format = Format::from_path(Path::new(name)).unwrap_or(Format::Json);
+    let file = parse_file(format, &interpolate(content, region)?)?;
+    if !file.include.is_empty() {
+        bail!("includes are only supported in local pipelines files");
+    }
+    for (index, pipeline) in file.pipelines.iter().enumerate() {
+        pipeline
+            .validate()
+            .with_context(|| format!("invalid pipeline {}", pipeline.display_name(index)))?;
+    }
+    if file.pipelines.is_empty() {
+        bail!("no pipelines found");
+    }
+    Ok(Pipelines::new(file.pipelines).with_mode(file.mode.unwrap_or_default()))
+}
+
+fn parse_file(format: Format, content: &str) -> Result<PipelinesFile> {
+    if format.is_list(content)? {
+        Ok(PipelinesFile {
+            mode: None,
+            include: vec![],
+            pipelines: format.parse(content)?,
+        })
+    } else {
+        format.parse(content)
+    }
+}
+
+#[derive(Default)]
+struct Loaded {
+    mode: Option<RoutingMode>,
+    pipelines: Vec<Pipeline>,
+    /// Files being loaded, used to detect include cycles
+    stack: Vec<PathBuf>,
+    /// Region of the parameters and secrets referenced by the files
+    region: Region,
+}
+
+impl Loaded {
+    fn load_file(&mut self, path: &Path) -> Result<()> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("failed to open pipelines file {}", path.display()))?;
+        if self.stack.contains(&canonical) {
+            bail!("{} is included by itself", path.display());
+        }
+
+        let format = Format::from_path(path)?;
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read pipelines file {}", path.display()))?;
+        let content = interpolate(&content, &self.region)
+            .with_context(|| format!("failed to interpolate pipelines file {}", path.display()))?;
+        let file = parse_file(format, &content)
+            .with_context(|| format!("failed to parse pipelines file {}", path.display()))?;
+
+        if let Some(mode) = file.mode {
+            match self.mode {
+                Some(current) if current != mode => bail!(
+                    "{} sets mode {:?}, but another file already set it to {:?}",
+                    path.display(),
+                    mode,
+                    current
+                ),
+                _ => self.mode = Some(mode),
+            }
+        }
+
+        self.stack.push(canonical);
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for include in &file.include {
+            self.load_file(&base.join(include))
+                .with_context(|| format!("included from {}", path.display()))?;
+        }
+        self.stack.pop();
+
+        for (index, pipeline) in file.pipelines.iter().enumerate() {
+            pipeline.validate().with_context(|| {
+                format!(
+                    "invalid pipeline {} in {}",
+                    pipeline.display_name(index),
+                    path.display()
+                )
+            })?;
+        }
+        self.pipelines.extend(file.pipelines);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use rusoto_core::Region;
+
+    use crate::pipelines::RoutingMode;
+    use crate::pipelines_file::{json_schema, load};
+
+    fn write(dir: &Path, name: &str, content: &str) {
+        fs::write(dir.join(name), content).unwrap();
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pipelines-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_load_formats_and_includes() {
+        let dir = temp_dir("formats");
+        write(
+            &dir,
+            "main.toml",
+            r#"
+mode = "first_match"
+include = ["errors.yaml"]
+
+[[pipelines]]
+name = "all"
+filter = "elb_status_code > 0"
+outputs = [{ type = "void" }]
+"#,
+        );
+        write(
+            &dir,
+            "errors.yaml",
+            r#"
+- name: errors
+  filter: elb_status_code >= 500
+  output:
+    type: void
+"#,
+        );
+        let pipelines = load(&dir.join("main.toml"), &Region::default()).unwrap();
+        assert_eq!(RoutingMode::FirstMatch, pipelines.mode());
+        let names: Vec<_> = pipelines
+            .inner()
+            .iter()
+            .map(|pipeline| pipeline.name.clone().unwrap())
+            .collect();
+        assert_eq!(vec!["errors", "all"], names);
+
+        write(&dir, "loop.json", r#"{"include": ["loop.json"]}"#);
+        assert!(load(&dir.join("loop.json"), &Region::default()).is_err());
+    }
+
+    #[test]
+    fn test_load_directory() {
+        let dir = temp_dir("directory");
+        write(
+            &dir,
+            "b.yaml",
+            "- {filter: elb_status_code > 0, output: {type: void}}",
+        );
+        write(
+            &dir,
+            "a.yaml",
+            "pipelines:\n  - {filter: elb_status_code >= 500, output: {type: void}}",
+        );
+        write(&dir, "ignored.json", "[]");
+        let pipelines = load(&dir, &Region::default()).unwrap();
+        let filters: Vec<_> = pipelines
+            .inner()
+            .iter()
+            .map(|pipeline| pipeline.filter.as_str())
+            .collect();
+        assert_eq!(
+            vec!["elb_status_code >= 500", "elb_status_code > 0"],
+            filters
+        );
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        let dir = temp_dir("unknown");
+        write(
+            &dir,
+            "typo.yaml",
+            "- filter: elb_status_code > 0\n  output:\n    type: cloudwatch_log\n    group_name: bots\n    stream_name: logs\n",
+        );
+        let error = format!(
+            "{:#}",
+            load(&dir.join("typo.yaml"), &Region::default()).unwrap_err()
+        );
+        assert!(error.contains("stream_name"), "{}", error);
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = serde_json::to_string(&json_schema()).unwrap();
+        assert!(schema.contains("stream_name_prefix"));
+        assert!(schema.contains("elb_status_code (Int)"));
+    }
+
+    #[test]
+    fn test_error_points_to_file_and_line() {
+        let dir = temp_dir("errors");
+        write(
+            &dir,
+            "bad.yaml",
+            "- filter: elb_status_code > 0\n  output: {type: void\n",
+        );
+        let error = format!(
+            "{:#}",
+            load(&dir.join("bad.yaml"), &Region::default()).unwrap_err()
+        );
+        assert!(error.contains("bad.yaml"), "{}", error);
+        assert!(error.contains("line"), "{}", error);
+    }
+}
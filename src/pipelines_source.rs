@@ -0,0 +1,279 @@
+use std::io::Read;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use rusoto_core::Region;
+use rusoto_s3::{GetObjectRequest, HeadObjectRequest, S3};
+use rusoto_ssm::{GetParameterRequest, Ssm, SsmClient};
+
+use crate::pipelines::Pipelines;
+use crate::pipelines_file::parse_document;
+use crate::s3::get_s3_client;
+
+/// Where to fetch the pipelines from, `s3://bucket/key` or `ssm:/path/param`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PipelinesSource {
+    S3 { bucket: String, key: String },
+    Ssm { name: String },
+}
+
+impl FromStr for PipelinesSource {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        if let Some(location) = source.strip_prefix("s3://") {
+            let mut parts = location.splitn(2, '/');
+            match (parts.next(), parts.next()) {
+                (Some(bucket), Some(key)) if !bucket.is_empty() && !key.is_empty() => {
+                    Ok(PipelinesSource::S3 {
+                        bucket: bucket.to_string(),
+                        key: key.to_string(),
+                    })
+                }
+                _ => bail!("expected s3://bucket/key, got {:?}", source),
+            }
+        } else if let Some(name) = source.strip_prefix("ssm:") {
+            if name.is_empty() {
+                bail!("expected ssm:/path/param, got {:?}", source);
+            }
+            Ok(PipelinesSource::Ssm {
+                name: name.to_string(),
+            })
+        } else {
+            bail!(
+                "unsupported pipelines source {:?}, expected s3://bucket/key or ssm:/path/param",
+                source
+            )
+        }
+    }
+}
+
+/// A fetched configuration and the ETag or parameter version it had
+#[derive(Debug)]
+struct Document {
+    version: String,
+    content: String,
+}
+
+impl PipelinesSource {
+    /// Fetches the configuration, unless its version is still `current_version`
+    fn fetch(&self, region: &Region, current_version: Option<&str>) -> Result<Option<Document>> {
+        match self {
+            PipelinesSource::S3 { bucket, key } => {
+                let client = get_s3_client(region);
+                let head = client
+                    .head_object(HeadObjectRequest {
+                        bucket: bucket.clone(),
+                        key: key.clone(),
+                        ..Default::default()
+                    })
+                    .sync()
+                    .with_context(|| format!("failed to check s3://{}/{}", bucket, key))?;
+                if head.e_tag.is_some() && head.e_tag.as_deref() == current_version {
+                    return Ok(None);
+                }
+                let response = client
+                    .get_object(GetObjectRequest {
+                        bucket: bucket.clone(),
+                        key: key.clone(),
+                        ..Default::default()
+                    })
+                    .sync()
+                    .with_context(|| format!("failed to download s3://{}/{}", bucket, key))?;
+                let mut content = String::new();
+                response
+                    .body
+                    .context("No body found for this key")?
+                    .into_blocking_read()
+                    .read_to_string(&mut content)?;
+                Ok(Some(Document {
+                    version: response.e_tag.unwrap_or_default(),
+                    content,
+                }))
+            }
+            PipelinesSource::Ssm { name } => {
+                let parameter = SsmClient::new(region.clone())
+                    .get_parameter(GetParameterRequest {
+                        name: name.clone(),
+                        with_decryption: Some(true),
+                    })
+                    .sync()
+                    .with_context(|| format!("failed to get parameter {}", name))?
+                    .parameter
+                    .with_context(|| format!("parameter {} not found", name))?;
+                let version = parameter.version.unwrap_or_default().to_string();
+                if Some(version.as_str()) == current_version {
+                    return Ok(None);
+                }
+                Ok(Some(Document {
+                    version,
+                    content: parameter.value.unwrap_or_default(),
+                }))
+            }
+        }
+    }
+
+    fn document_name(&self) -> &str {
+        match self {
+            PipelinesSource::S3 { key, .. } => key,
+            PipelinesSource::Ssm { name } => name,
+        }
+    }
+}
+
+/// Tracks the version of the pipelines in use, checking the source again once `ttl` expires
+pub(crate) struct PipelinesCache {
+    source: PipelinesSource,
+    ttl: Duration,
+    version: Option<String>,
+    checked_at: Option<Instant>,
+}
+
+impl PipelinesCache {
+    pub fn new(source: PipelinesSource, ttl: Duration) -> Self {
+        Self {
+            source,
+            ttl,
+            version: None,
+            checked_at: None,
+        }
+    }
+
+    /// Returns the pipelines the first time and whenever a new version compiles. Once some
+    /// pipelines were loaded, errors are only logged so the previous ones are kept.
+    pub fn refresh(&mut self, region: &Region) -> Result<Option<Pipelines>> {
+        let source = self.source.clone();
+        self.refresh_with(region, |version| source.fetch(region, version))
+    }
+
+    /// Refreshes the pipelines with `fetch`, called with the version in use
+    fn refresh_with<F>(&mut self, region: &Region, fetch: F) -> Result<Option<Pipelines>>
+    where
+        F: FnOnce(Option<&str>) -> Result<Option<Document>>,
+    {
+        if let Some(checked_at) = self.checked_at {
+            if checked_at.elapsed() < self.ttl {
+                return Ok(None);
+            }
+        }
+        match self.load(region, fetch) {
+            Ok(pipelines) => {
+                self.checked_at = Some(Instant::now());
+                Ok(pipelines)
+            }
+            Err(error) if self.version.is_some() => {
+                error!("Keeping the previous pipelines: {:#}", error);
+                self.checked_at = Some(Instant::now());
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn load<F>(&mut self, region: &Region, fetch: F) -> Result<Option<Pipelines>>
+    where
+        F: FnOnce(Option<&str>) -> Result<Option<Document>>,
+    {
+        let document = match fetch(self.version.as_deref())? {
+            Some(document) => document,
+            None => return Ok(None),
+        };
+        let pipelines = parse_document(&document.content, self.source.document_name(), region)
+            .with_context(|| format!("invalid pipelines in {:?}", self.source))?;
+        info!(
+            "Loaded pipelines version {} from {:?}",
+            document.version, self.source
+        );
+        self.version = Some(document.version);
+        Ok(Some(pipelines))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use rusoto_core::Region;
+
+    use crate::pipelines_source::{Document, PipelinesCache, PipelinesSource};
+
+    const PIPELINES: &str = r#"[{"filter": "elb_status_code >= 500", "output": {"type": "void"}}]"#;
+
+    fn document(version: &str, content: &str) -> Document {
+        Document {
+            version: version.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_keep_previous_pipelines() {
+        let source: PipelinesSource = "ssm:/elb/pipelines".parse().unwrap();
+        let mut cache = PipelinesCache::new(source.clone(), Duration::from_secs(0));
+        assert!(cache
+            .refresh_with(&Region::default(), |_| Err(anyhow!("parameter not found")))
+            .is_err());
+        assert!(cache
+            .refresh_with(&Region::default(), |_| Ok(Some(document("1", PIPELINES))))
+            .unwrap()
+            .is_some());
+
+        // Failures keep the version in use, so it is fetched again next time
+        assert!(cache
+            .refresh_with(&Region::default(), |_| Err(anyhow!("throttled")))
+            .unwrap()
+            .is_none());
+        assert!(cache
+            .refresh_with(&Region::default(), |_| Ok(Some(document(
+                "2",
+                r#"[{"filter": "elb_status_code >>"}]"#
+            ))))
+            .unwrap()
+            .is_none());
+        assert!(cache
+            .refresh_with(&Region::default(), |version| {
+                assert_eq!(Some("1"), version);
+                Ok(None)
+            })
+            .unwrap()
+            .is_none());
+        assert!(cache
+            .refresh_with(&Region::default(), |_| Ok(Some(document("3", PIPELINES))))
+            .unwrap()
+            .is_some());
+
+        let mut cache = PipelinesCache::new(source, Duration::from_secs(3600));
+        assert!(cache
+            .refresh_with(&Region::default(), |_| Ok(Some(document("1", PIPELINES))))
+            .unwrap()
+            .is_some());
+        assert!(cache
+            .refresh_with(&Region::default(), |_| panic!(
+                "checked before the ttl expired"
+            ))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            PipelinesSource::S3 {
+                bucket: "config".to_string(),
+                key: "elb/pipelines.yaml".to_string()
+            },
+            "s3://config/elb/pipelines.yaml".parse().unwrap()
+        );
+        assert_eq!(
+            PipelinesSource::Ssm {
+                name: "/elb/pipelines".to_string()
+            },
+            "ssm:/elb/pipelines".parse().unwrap()
+        );
+        assert!("s3://config".parse::<PipelinesSource>().is_err());
+        assert!("/elb/pipelines".parse::<PipelinesSource>().is_err());
+    }
+}
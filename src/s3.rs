@@ -1,28 +1,97 @@
 use std::io::Read;
 
 use anyhow::{Context as _, Result};
-use flate2::read::MultiGzDecoder;
 use log::info;
 use rusoto_core::Region;
-use rusoto_s3::{S3Client, S3};
+use rusoto_s3::{GetObjectRequest, HeadObjectRequest, PutObjectRequest, S3Client, S3};
 
-use crate::config::Config;
-
-fn get_s3_client(region: &Region) -> S3Client {
+pub(crate) fn get_s3_client(region: &Region) -> S3Client {
     S3Client::new(region.clone())
 }
 
-pub(crate) fn open_s3_file(bucket: &str, key: &str, config: &Config) -> Result<impl Read> {
-    info!("Starting to download from s3://{}/{}", bucket, key);
-    let request = rusoto_s3::GetObjectRequest {
+/// The ETag of the current version of an object, to checkpoint it before downloading it
+pub(crate) fn s3_file_etag(client: &S3Client, bucket: &str, key: &str) -> Result<Option<String>> {
+    let request = HeadObjectRequest {
         bucket: bucket.to_owned(),
         key: key.to_owned(),
         ..Default::default()
     };
-    let client = get_s3_client(&config.aws_region);
+    let response = client
+        .head_object(request)
+        .sync()
+        .with_context(|| format!("failed to get the ETag of s3://{}/{}", bucket, key))?;
+    Ok(response.e_tag)
+}
+
+pub(crate) fn open_s3_file(client: &S3Client, bucket: &str, key: &str) -> Result<impl Read> {
+    info!("Starting to download from s3://{}/{}", bucket, key);
+    get_object(
+        client,
+        GetObjectRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        },
+    )
+}
+
+/// Downloads the version of a log file with `etag`, from a byte offset when resuming it
+pub(crate) fn open_s3_file_at(
+    client: &S3Client,
+    bucket: &str,
+    key: &str,
+    etag: &str,
+    offset: u64,
+) -> Result<impl Read> {
+    info!(
+        "Starting to download from s3://{}/{} at byte {}",
+        bucket, key, offset
+    );
+    get_object(
+        client,
+        GetObjectRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            if_match: Some(etag.to_owned()),
+            range: if offset > 0 {
+                Some(format!("bytes={}-", offset))
+            } else {
+                None
+            },
+            ..Default::default()
+        },
+    )
+}
+
+fn get_object(client: &S3Client, request: GetObjectRequest) -> Result<impl Read> {
     let response = client.get_object(request).sync()?;
 
     let body = response.body.context("No body found for this key")?;
 
-    Ok(MultiGzDecoder::new(body.into_blocking_read()))
+    Ok(body.into_blocking_read())
+}
+
+pub(crate) fn put_object(
+    client: &S3Client,
+    bucket: &str,
+    key: &str,
+    data: Vec<u8>,
+    content_type: &str,
+    content_encoding: Option<&str>,
+) -> Result<()> {
+    let request = PutObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        content_type: Some(content_type.to_owned()),
+        content_encoding: content_encoding.map(str::to_owned),
+        content_length: Some(data.len() as i64),
+        body: Some(data.into()),
+        ..Default::default()
+    };
+    client
+        .put_object(request)
+        .sync()
+        .with_context(|| format!("failed to upload s3://{}/{}", bucket, key))?;
+    info!("Uploaded s3://{}/{}", bucket, key);
+    Ok(())
 }
@@ -0,0 +1,64 @@
+use anyhow::Result;
+use log::error;
+use rusoto_s3::S3Client;
+
+use crate::checkpoints::CheckpointStore;
+use crate::config::Config;
+use crate::pipelines::{compile_pipelines, CompiledPipelines};
+use crate::pipelines_source::PipelinesCache;
+use crate::s3::get_s3_client;
+
+/// Everything kept between the invocations served by a lambda container: the configuration,
+/// the compiled pipelines with their outputs (clients, log streams, sequence tokens), the
+/// client used to download the logs and the checkpoint store.
+pub(crate) struct State {
+    pub config: Config,
+    pub s3_client: S3Client,
+    cache: Option<PipelinesCache>,
+    pipelines: CompiledPipelines,
+    checkpoints: Option<Box<dyn CheckpointStore>>,
+}
+
+impl State {
+    pub fn new(mut config: Config) -> Result<Self> {
+        let mut cache = config.pipelines_cache();
+        let pipelines = match &mut cache {
+            Some(cache) => cache
+                .refresh(&config.aws_region())?
+                .expect("pipelines are returned by the first refresh"),
+            None => config.take_pipelines().expect("pipelines are configured"),
+        };
+        Ok(Self {
+            s3_client: get_s3_client(&config.aws_region()),
+            pipelines: compile_pipelines(pipelines),
+            checkpoints: config.checkpoint_store(),
+            cache,
+            config,
+        })
+    }
+
+    pub fn pipelines(&self) -> &CompiledPipelines {
+        &self.pipelines
+    }
+
+    pub fn checkpoints(&self) -> Option<&dyn CheckpointStore> {
+        self.checkpoints.as_deref()
+    }
+
+    /// Swaps the pipelines when their source has a new version that compiles
+    pub fn refresh_pipelines(&mut self) {
+        let cache = match &mut self.cache {
+            Some(cache) => cache,
+            None => return,
+        };
+        match cache.refresh(&self.config.aws_region()) {
+            Ok(Some(pipelines)) => {
+                // Errors are logged, the previous outputs are not used anymore
+                let _ = self.pipelines.finish();
+                self.pipelines = compile_pipelines(pipelines);
+            }
+            Ok(None) => {}
+            Err(error) => error!("Failed to refresh pipelines {:?}", error),
+        }
+    }
+}
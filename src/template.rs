@@ -0,0 +1,139 @@
+use anyhow::{bail, Result};
+
+use crate::types::RequestLogLine;
+
+const DATE_TOKENS: [(&str, &str); 6] = [
+    ("yyyy", "%Y"),
+    ("MM", "%m"),
+    ("dd", "%d"),
+    ("HH", "%H"),
+    ("mm", "%M"),
+    ("ss", "%S"),
+];
+
+/// Renders a template like `dt={dt}/elb={elb_name}/` using the values of a log line.
+///
+/// Placeholders can be any field name known by `RequestLogLine::field_value`, `dt` for the
+/// date of the request (`YYYY-MM-DD`) or a date pattern like `{yyyy.MM.dd}` or `{HH}`.
+pub fn render(template: &str, line: &RequestLogLine) -> Result<String> {
+    render_with(template, line, |value| value)
+}
+
+/// Renders a template into an S3 key. `%` and `/` in field values are percent-encoded, like
+/// Hive escapes partition values, so a value like `app/my-alb/1a2b` doesn't add path levels.
+pub fn render_key(template: &str, line: &RequestLogLine) -> Result<String> {
+    render_with(template, line, |value| {
+        value.replace('%', "%25").replace('/', "%2F")
+    })
+}
+
+fn render_with(
+    template: &str,
+    line: &RequestLogLine,
+    escape: fn(String) -> String,
+) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => bail!("unclosed placeholder in template {:?}", template),
+        };
+        let placeholder = &rest[start + 1..end];
+        rendered.push_str(
+            &render_placeholder(placeholder, line, escape).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unknown placeholder {{{}}} in template {:?}",
+                    placeholder,
+                    template
+                )
+            })?,
+        );
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+fn render_placeholder(
+    placeholder: &str,
+    line: &RequestLogLine,
+    escape: fn(String) -> String,
+) -> Option<String> {
+    if placeholder == "dt" {
+        return Some(line.timestamp.format("%Y-%m-%d").to_string());
+    }
+    if let Some(value) = line.field_value(placeholder) {
+        return Some(escape(value));
+    }
+    date_format(placeholder).map(|format| line.timestamp.format(&format).to_string())
+}
+
+/// Converts a pattern like `yyyy.MM.dd` into a chrono format string
+fn date_format(pattern: &str) -> Option<String> {
+    let mut format = String::new();
+    let mut rest = pattern;
+    let mut has_token = false;
+
+    'outer: while !rest.is_empty() {
+        for (token, replacement) in DATE_TOKENS.iter() {
+            if let Some(stripped) = rest.strip_prefix(token) {
+                format.push_str(replacement);
+                rest = stripped;
+                has_token = true;
+                continue 'outer;
+            }
+        }
+        let separator = rest.chars().next().unwrap();
+        if !"-_./: ".contains(separator) {
+            return None;
+        }
+        format.push(separator);
+        rest = &rest[separator.len_utf8()..];
+    }
+
+    if has_token {
+        Some(format)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_processing::fixtures::first_log_line;
+    use crate::template::{render, render_key};
+
+    #[test]
+    fn test_render_partitions() {
+        let line = first_log_line();
+        assert_eq!(
+            "dt=2020-02-12/elb=app%2Fprivate-ecs-on-production%2F8cb653e6ebead26b/",
+            render_key("dt={dt}/elb={elb_name}/", &line).unwrap()
+        );
+        // Date patterns can still add path levels
+        assert_eq!(
+            "2020/02/12/app%2Fprivate-ecs-on-production%2F8cb653e6ebead26b",
+            render_key("{yyyy/MM/dd}/{elb_name}", &line).unwrap()
+        );
+        assert_eq!(
+            "elb=app/private-ecs-on-production/8cb653e6ebead26b",
+            render("elb={elb_name}", &line).unwrap()
+        );
+        assert_eq!("alb-2020.02.12", render("alb-{yyyy.MM.dd}", &line).unwrap());
+        assert_eq!(
+            "internal-service-production-2xx",
+            render("{target_group}-{status_class}", &line).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let line = first_log_line();
+        assert!(render("{not_a_field}", &line).is_err());
+        assert!(render("{elb_name", &line).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 
@@ -13,6 +15,24 @@ pub enum MaybeNumber<T> {
     FailedToParse(String),
 }
 
+impl<T: Copy> MaybeNumber<T> {
+    pub fn number(&self) -> Option<T> {
+        match self {
+            MaybeNumber::Number(number) => Some(*number),
+            MaybeNumber::FailedToParse(_) => None,
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for MaybeNumber<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaybeNumber::Number(number) => number.fmt(f),
+            MaybeNumber::FailedToParse(value) => f.write_str(value),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Request {
     pub method: String,
@@ -20,6 +40,18 @@ pub struct Request {
     pub http_version: String,
 }
 
+impl Request {
+    /// Splits a request, `None` when it lacks a method, a path or an HTTP version
+    pub fn parse(data: &str) -> Option<Self> {
+        let mut parts = data.split(' ');
+        Some(Request {
+            method: parts.next()?.to_owned(),
+            path: parts.next()?.to_owned(),
+            http_version: parts.next()?.to_owned(),
+        })
+    }
+}
+
 impl From<&str> for Request {
     fn from(data: &str) -> Self {
         let mut parts = data.split(' ');
@@ -31,6 +63,55 @@ impl From<&str> for Request {
     }
 }
 
+/// Names of the fields of a log line, in the order AWS writes them
+pub const FIELD_NAMES: [&str; 24] = [
+    "request_type",
+    "timestamp",
+    "elb_name",
+    "client",
+    "target",
+    "request_processing_time",
+    "target_processing_time",
+    "response_processing_time",
+    "elb_status_code",
+    "target_status_code",
+    "received_bytes",
+    "sent_bytes",
+    "request",
+    "user_agent",
+    "ssl_cipher",
+    "ssl_protocol",
+    "target_group_arn",
+    "trace_id",
+    "domain_name",
+    "chosen_cert_arn",
+    "matched_rule_priority",
+    "request_creation_time",
+    "actions_executed",
+    "error_reason",
+];
+
+/// Fields computed from others, also available by name
+pub const DERIVED_FIELD_NAMES: [&str; 5] = [
+    "method",
+    "path",
+    "http_version",
+    "target_group",
+    "status_class",
+];
+
+/// Fields holding a duration in seconds, -1 when the target could not be reached
+pub const LATENCY_FIELD_NAMES: [&str; 3] = [
+    "request_processing_time",
+    "target_processing_time",
+    "response_processing_time",
+];
+
+/// Whether `field_value` knows a field, to check configurations before any line is processed
+pub fn is_field_name(name: &str) -> bool {
+    FIELD_NAMES.contains(&name) || DERIVED_FIELD_NAMES.contains(&name)
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RequestLogLine {
     pub request_type: String,
@@ -65,6 +146,61 @@ impl RequestLogLine {
         Request::from(self.request.as_str())
     }
 
+    /// Like `request`, but `None` for a malformed request instead of panicking
+    pub fn try_request(&self) -> Option<Request> {
+        Request::parse(&self.request)
+    }
+
+    /// Name of the target group, without the rest of its ARN
+    pub fn target_group(&self) -> &str {
+        self.target_group_arn
+            .split('/')
+            .nth(1)
+            .unwrap_or(&self.target_group_arn)
+    }
+
+    /// Status class of the response sent by the load balancer, e.g. `5xx`
+    pub fn status_class(&self) -> String {
+        format!("{}xx", self.elb_status_code / 100)
+    }
+
+    /// Returns the value of a field (or a derived request field) by its name
+    pub fn field_value(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "request_type" => self.request_type.clone(),
+            "timestamp" => self.timestamp.to_rfc3339(),
+            "elb_name" => self.elb_name.clone(),
+            "client" => self.client.clone(),
+            "target" => self.target.clone(),
+            "request_processing_time" => self.request_processing_time.to_string(),
+            "target_processing_time" => self.target_processing_time.to_string(),
+            "response_processing_time" => self.response_processing_time.to_string(),
+            "elb_status_code" => self.elb_status_code.to_string(),
+            "target_status_code" => self.target_status_code.to_string(),
+            "received_bytes" => self.received_bytes.to_string(),
+            "sent_bytes" => self.sent_bytes.to_string(),
+            "request" => self.request.clone(),
+            "method" => self.try_request()?.method,
+            "path" => self.try_request()?.path,
+            "http_version" => self.try_request()?.http_version,
+            "user_agent" => self.user_agent.clone(),
+            "ssl_cipher" => self.ssl_cipher.clone(),
+            "ssl_protocol" => self.ssl_protocol.clone(),
+            "target_group_arn" => self.target_group_arn.clone(),
+            "target_group" => self.target_group().to_owned(),
+            "status_class" => self.status_class(),
+            "trace_id" => self.trace_id.clone(),
+            "domain_name" => self.domain_name.clone(),
+            "chosen_cert_arn" => self.chosen_cert_arn.clone(),
+            "matched_rule_priority" => self.matched_rule_priority.clone(),
+            "request_creation_time" => self.request_creation_time.to_rfc3339(),
+            "actions_executed" => self.actions_executed.clone(),
+            "error_reason" => self.error_reason.clone(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
     pub fn execution_context<'s, 'e>(&'s self) -> Result<wirefilter::ExecutionContext<'e>>
     where
         's: 'e,
@@ -85,4 +221,15 @@ impl RequestLogLine {
 
 pub trait LogProcessor {
     fn process_line(&self, log_line: &RequestLogLine) -> Result<()>;
+
+    /// Called once a log file has been fully processed
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Lines accepted but not sent yet, they are counted as emitted or dropped once the output
+    /// flushes them
+    fn buffered_lines(&self) -> usize {
+        0
+    }
 }
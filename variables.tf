@@ -1,4 +1,15 @@
 variable "aws_region" {}
 variable "buckets" { type = list(string) }
-variable "pipelines" { type = string }
+variable "pipelines" {
+  type    = string
+  default = ""
+}
+variable "pipelines_source" {
+  description = "s3://bucket/key or ssm:/path/param to fetch the pipelines from instead of the pipelines variable"
+  default     = ""
+}
+variable "checkpoints" {
+  description = "dynamodb:table to skip the log files already processed, the table needs an `id` string partition key"
+  default     = ""
+}
 variable "reserved_concurrent_executions" { default = 1 }